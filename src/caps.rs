@@ -0,0 +1,156 @@
+use std::{fmt, str::FromStr};
+
+use nix::{errno::Errno, libc};
+
+/// Capability names, indexed by their number, see capabilities(7)
+const NAMES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// A capability, given by name like `CAP_NET_RAW` or `net_raw`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Capability(u8);
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let name = if upper.starts_with("CAP_") {
+            upper
+        } else {
+            format!("CAP_{upper}")
+        };
+        NAMES
+            .iter()
+            .position(|&known| known == name)
+            .map(|i| Capability(i as u8))
+            .ok_or_else(|| format!("unknown capability {s:?}"))
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(NAMES[self.0 as usize])
+    }
+}
+
+impl fmt::Debug for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn prctl(option: libc::c_int, arg2: libc::c_ulong, arg3: libc::c_ulong) -> Result<(), Errno> {
+    Errno::result(unsafe {
+        libc::prctl(option, arg2, arg3, 0 as libc::c_ulong, 0 as libc::c_ulong)
+    })
+    .map(drop)
+}
+
+/// Drop every capability but keep from the bounding, ambient, effective, permitted and
+/// inheritable sets of the current process. The kept ones that are permitted are raised
+/// in the ambient set, so that they survive execve(2) even for a user other than root.
+pub fn drop_except(keep: &[Capability]) -> Result<(), Errno> {
+    let mask = keep.iter().fold(0u64, |mask, cap| mask | 1 << cap.0);
+
+    // Dropping from the bounding set needs CAP_SETPCAP, so do it before capset
+    for cap in 0..64 {
+        if mask & 1 << cap == 0 {
+            match prctl(libc::PR_CAPBSET_DROP, cap, 0) {
+                // Capabilities the kernel doesn't know about
+                Err(Errno::EINVAL) => break,
+                result => result?,
+            }
+        }
+    }
+
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    Errno::result(unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) })?;
+    let permitted = (data[0].permitted as u64 | (data[1].permitted as u64) << 32) & mask;
+    for (i, data) in data.iter_mut().enumerate() {
+        let half = (permitted >> (32 * i)) as u32;
+        *data = CapData {
+            effective: half,
+            permitted: half,
+            inheritable: half,
+        };
+    }
+    Errno::result(unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) })?;
+
+    match prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as _, 0) {
+        // Kernels before 4.3 have no ambient set
+        Err(Errno::EINVAL) => return Ok(()),
+        result => result?,
+    }
+    for cap in keep {
+        if permitted & 1 << cap.0 != 0 {
+            prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as _,
+                cap.0 as _,
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// Settings read from `apprun.toml`, which the command line overrides.
+/// Relative paths in it are relative to the directory of the file, except for an
+/// entrypoint without a slash, which is a command to search for in PATH.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Like --bind, in the same SRC[:DST] format
+    pub binds: Option<Vec<String>>,
+    pub nix_dir: Option<PathBuf>,
+    pub entrypoint: Option<PathBuf>,
+    pub mount_timeout: Option<f32>,
+    /// Set in the entrypoint's environment, before --setenv
+    pub env: BTreeMap<String, String>,
+    pub unshare_pid: bool,
+    pub unshare_net: bool,
+    pub unshare_uts: bool,
+    pub hostname: Option<String>,
+    /// Extra mounts made inside the container after the others
+    pub mounts: Option<Vec<Mount>>,
+    /// Settings for AppRun started under another name, e.g. through a symlink, by that name.
+    /// They take precedence over the ones above.
+    pub profiles: BTreeMap<String, Config>,
+}
+
+/// A mount of the `mounts` array, like `mount -t FSTYPE -o OPTIONS SOURCE TARGET`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mount {
+    pub fstype: String,
+    /// Defaults to fstype, as usual for pseudo filesystems like tmpfs
+    pub source: Option<String>,
+    /// The path inside the container
+    pub target: PathBuf,
+    /// Comma-separated, like `ro,size=16M`
+    #[serde(default)]
+    pub options: String,
+}
+
+impl Config {
+    /// Read the config file at path
+    pub fn read(path: &Path) -> Result<Config, io::Error> {
+        let mut config: Config = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some((name, _)) = config
+            .profiles
+            .iter()
+            .find(|(_, profile)| !profile.profiles.is_empty())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("profile {name:?} has profiles of its own"),
+            ));
+        }
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        config.resolve_paths(dir);
+        for profile in config.profiles.values_mut() {
+            profile.resolve_paths(dir);
+        }
+        Ok(config)
+    }
+
+    /// Make the relative paths relative to dir
+    fn resolve_paths(&mut self, dir: &Path) {
+        let entrypoint = self
+            .entrypoint
+            .as_mut()
+            .filter(|entrypoint| entrypoint.as_os_str().as_bytes().contains(&b'/'));
+        for path in [self.nix_dir.as_mut(), entrypoint].into_iter().flatten() {
+            *path = dir.join(&*path);
+        }
+    }
+
+    /// The settings for AppRun started as name: those of the profile of that name, if
+    /// there is one, on top of the others
+    pub fn for_name(mut self, name: &str) -> Config {
+        let Some(profile) = self.profiles.remove(name) else {
+            return self;
+        };
+        let mut env = self.env;
+        env.extend(profile.env);
+        Config {
+            binds: profile.binds.or(self.binds),
+            nix_dir: profile.nix_dir.or(self.nix_dir),
+            entrypoint: profile.entrypoint.or(self.entrypoint),
+            mount_timeout: profile.mount_timeout.or(self.mount_timeout),
+            env,
+            unshare_pid: profile.unshare_pid || self.unshare_pid,
+            unshare_net: profile.unshare_net || self.unshare_net,
+            unshare_uts: profile.unshare_uts || self.unshare_uts,
+            hostname: profile.hostname.or(self.hostname),
+            mounts: profile.mounts.or(self.mounts),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::{self, Write};
+
+use log::debug;
+use nix::{
+    fcntl::{open, OFlag},
+    sys::{
+        stat::{umask, Mode},
+        wait::waitpid,
+    },
+    unistd::{close, dup2, fork, setsid, ForkResult},
+};
+
+/// Detach from the terminal and continue in the background.
+///
+/// The process forks twice, with a setsid(2) in between, so that the daemon
+/// is neither a session leader nor a child of the caller. The PID of the daemon
+/// is printed to stdout, then the caller exits, and only the daemon returns,
+/// with its stdio redirected to /dev/null and its umask set to mask.
+pub fn daemonize(mask: Mode) -> Result<(), io::Error> {
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            // Exit only once the PID has been printed
+            waitpid(child, None)?;
+            std::process::exit(0);
+        }
+        ForkResult::Child => {}
+    }
+
+    setsid()?;
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            println!("{child}");
+            let _ = io::stdout().flush();
+            unsafe { nix::libc::_exit(0) };
+        }
+        ForkResult::Child => {}
+    }
+
+    umask(mask);
+    let null = open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+    for fd in 0..=2 {
+        dup2(null, fd)?;
+    }
+    close(null)?;
+
+    debug!("Running as daemon");
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::{
+    fmt, fs, io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use nix::sys::stat::{major, minor};
+
+use crate::mountinfo;
+
+/// How a device is identified in the links udev creates below /dev/disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Label,
+    Uuid,
+}
+
+impl Tag {
+    /// The directory of disk_dir with the links for this kind of tag
+    fn dir(self) -> &'static str {
+        match self {
+            Tag::Label => "by-label",
+            Tag::Uuid => "by-uuid",
+        }
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tag::Label => write!(f, "label"),
+            Tag::Uuid => write!(f, "UUID"),
+        }
+    }
+}
+
+/// Find where the device with the given label or UUID is mounted, preferring a mount of the
+/// whole filesystem over a bind of a directory in it.
+/// Returns None if there is no such device, or it isn't mounted.
+pub fn find_mount(disk_dir: &Path, tag: Tag, name: &str) -> io::Result<Option<PathBuf>> {
+    let link = disk_dir.join(tag.dir()).join(name);
+    let metadata = match fs::metadata(&link) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        result => result?,
+    };
+    if !metadata.file_type().is_block_device() {
+        return Ok(None);
+    }
+    let device = (major(metadata.rdev()), minor(metadata.rdev()));
+    let mounts: Vec<_> = mountinfo::read()?
+        .into_iter()
+        .filter(|m| m.device == device)
+        .collect();
+    let whole = mounts
+        .iter()
+        .position(|m| m.root == Path::new("/"))
+        .unwrap_or(0);
+    Ok(mounts.into_iter().nth(whole).map(|m| m.mount_point))
+}
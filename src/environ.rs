@@ -0,0 +1,106 @@
+use std::{ffi::CString, fmt::Write as _, fs, io, path::Path};
+
+/// Encode an environment entry as a single line of text.
+///
+/// Printable UTF-8 is kept as-is, a backslash becomes `\\`, a newline becomes `\n`,
+/// and any other control character or byte that isn't valid UTF-8 becomes `\xNN`.
+/// A leading `#` is also written as `\x23`, so that it isn't read back as a comment.
+pub fn encode(bytes: &[u8]) -> String {
+    if let Some(rest) = bytes.strip_prefix(b"#") {
+        return format!("\\x23{}", encode(rest));
+    }
+    let mut line = String::new();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => line.push_str("\\\\"),
+                '\n' => line.push_str("\\n"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        write!(line, "\\x{b:02x}").unwrap();
+                    }
+                }
+                c => line.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            write!(line, "\\x{b:02x}").unwrap();
+        }
+    }
+    line
+}
+
+/// Decode a line produced by [`encode`]
+pub fn decode(line: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => bytes.push(b'\\'),
+            Some('n') => bytes.push(b'\n'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let b = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| format!("invalid escape \\x{hex} in {line:?}"))?;
+                bytes.push(b);
+            }
+            other => return Err(format!("invalid escape {other:?} in {line:?}")),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Write environment entries to a file, one encoded `KEY=VALUE` per line
+pub fn write_dump(path: &Path, env: &[CString]) -> Result<(), io::Error> {
+    let mut dump = String::new();
+    for entry in env {
+        dump.push_str(&encode(entry.as_bytes()));
+        dump.push('\n');
+    }
+    fs::write(path, dump)
+}
+
+/// Read environment entries written by [`write_dump`].
+/// Empty lines and lines starting with `#` are ignored.
+pub fn read_dump(path: &Path) -> Result<Vec<CString>, io::Error> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let entry = decode(line).map_err(invalid)?;
+            if !entry.contains(&b'=') {
+                return Err(invalid(format!("expected KEY=VALUE, got {line:?}")));
+            }
+            CString::new(entry).map_err(|e| invalid(e.to_string()))
+        })
+        .collect()
+}
+
+/// The key of a KEY=VALUE entry
+fn key(entry: &CString) -> &[u8] {
+    let bytes = entry.as_bytes();
+    let end = bytes.iter().position(|&b| b == b'=').unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// Add entries to an environment, replacing existing entries with the same key
+pub fn merge(env: &mut Vec<CString>, entries: Vec<CString>) {
+    for entry in entries {
+        match env.iter().position(|e| key(e) == key(&entry)) {
+            Some(i) => env[i] = entry,
+            None => env.push(entry),
+        }
+    }
+}
+
+/// Remove the entry with the given key from an environment
+pub fn remove(env: &mut Vec<CString>, name: &str) {
+    env.retain(|e| key(e) != name.as_bytes());
+}
@@ -0,0 +1,75 @@
+use std::{
+    env, fs, io,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    sys::{stat::Mode, wait::waitpid},
+    unistd::{close, dup2, fork, getpid, pipe, read, ForkResult},
+};
+
+/// The directory to create GC roots in, if the host has nix installed.
+/// Like nix itself, this honours `NIX_STATE_DIR`.
+pub fn roots_dir(user: Option<&str>) -> Option<PathBuf> {
+    let state_dir = env::var_os("NIX_STATE_DIR").unwrap_or_else(|| "/nix/var/nix".into());
+    let gcroots = Path::new(&state_dir).join("gcroots");
+    let per_user = user.map(|user| gcroots.join("per-user").join(user));
+    per_user
+        .filter(|dir| dir.is_dir())
+        .or_else(|| gcroots.is_dir().then_some(gcroots))
+}
+
+/// Register store_path as a GC root in roots_dir.
+///
+/// The root is removed by a watcher process once this process,
+/// and every process it execs or forks, has exited.
+/// For this, the write end of a pipe is deliberately leaked into the app.
+pub fn register(roots_dir: &Path, store_path: &Path) -> Result<PathBuf, io::Error> {
+    let root = roots_dir.join(format!("apprun-{}", getpid()));
+    symlink(store_path, &root)?;
+
+    // Neither end is close-on-exec
+    let (read_end, write_end) = pipe()?;
+
+    // Fork twice, so the watcher isn't a child of the app
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            if let Ok(ForkResult::Child) = unsafe { fork() } {
+                let _ = close(write_end);
+                watch(read_end, &root);
+            }
+            unsafe { nix::libc::_exit(0) };
+        }
+        ForkResult::Parent { child } => {
+            waitpid(child, None)?;
+            close(read_end)?;
+        }
+    }
+
+    debug!("Registered GC root {root:?} -> {store_path:?}");
+    Ok(root)
+}
+
+/// Wait until every writer of the pipe is gone, then remove the root
+fn watch(read_end: i32, root: &Path) {
+    // Don't keep the app's stdio open
+    if let Ok(null) = open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+        for fd in 0..=2 {
+            let _ = dup2(null, fd);
+        }
+    }
+
+    let mut buf = [0; 1];
+    loop {
+        match read(read_end, &mut buf) {
+            Err(Errno::EINTR) => continue,
+            Ok(n) if n > 0 => continue,
+            _ => break,
+        }
+    }
+    let _ = fs::remove_file(root);
+}
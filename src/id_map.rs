@@ -1,13 +1,52 @@
-use nix::unistd::{Gid, Uid};
+use nix::{
+    fcntl::OFlag,
+    sys::wait::{waitpid, WaitStatus},
+    unistd::{close, execv, fork, pipe2, read, ForkResult, Gid, Pid, Uid},
+};
 use paste::paste;
-use std::{fmt::Debug, path::PathBuf, str::FromStr};
+use std::{
+    env,
+    ffi::CString,
+    fmt::{self, Debug},
+    fs, io,
+    num::ParseIntError,
+    os::{fd::RawFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Error parsing a line of an id map
+#[derive(Debug, PartialEq, Eq)]
+pub enum IdMapError {
+    /// The line has fewer than three fields
+    MissingField(&'static str),
+    /// A field is not a valid id or count
+    InvalidNumber(ParseIntError),
+}
+
+impl fmt::Display for IdMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdMapError::MissingField(field) => write!(f, "missing {field} in id map line"),
+            IdMapError::InvalidNumber(e) => write!(f, "invalid number in id map line: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for IdMapError {}
+
+impl From<ParseIntError> for IdMapError {
+    fn from(e: ParseIntError) -> Self {
+        IdMapError::InvalidNumber(e)
+    }
+}
 
 macro_rules! id_map {
     ($id:ident) => {
         paste! { id_map!($id, [<$id Map>]); }
     };
     ($id:ident, $struct:ident) => {
-        #[derive(Debug)]
+        #[derive(Debug, PartialEq, Eq)]
         pub struct $struct {
             pub inside_id: $id,
             pub outside_id: $id,
@@ -15,13 +54,14 @@ macro_rules! id_map {
         }
 
         impl FromStr for $struct {
-            type Err = std::num::ParseIntError;
+            type Err = IdMapError;
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 let mut parts = s.split_whitespace();
-                let inside_id = parts.next().unwrap().parse::<nix::libc::uid_t>()?;
-                let outside_id = parts.next().unwrap().parse::<nix::libc::uid_t>()?;
-                let count = parts.next().unwrap().parse::<nix::libc::uid_t>()?;
+                let mut next = |field| parts.next().ok_or(IdMapError::MissingField(field));
+                let inside_id = next("inside id")?.parse::<nix::libc::uid_t>()?;
+                let outside_id = next("outside id")?.parse::<nix::libc::uid_t>()?;
+                let count = next("count")?.parse::<nix::libc::uid_t>()?;
                 Ok($struct {
                     inside_id: $id::from_raw(inside_id),
                     outside_id: $id::from_raw(outside_id),
@@ -30,9 +70,55 @@ macro_rules! id_map {
             }
         }
 
-        impl ToString for $struct {
-            fn to_string(&self) -> String {
-                format!(
+        impl $struct {
+            /// Map outside_id to inside_id, and the subordinate ranges to the lowest inside ids
+            /// left free, in order
+            pub fn with_ranges(
+                inside_id: $id,
+                outside_id: $id,
+                ranges: &[SubidRange],
+            ) -> Vec<Self> {
+                let own = inside_id.as_raw();
+                let mut maps = vec![$struct {
+                    inside_id,
+                    outside_id,
+                    count: 1,
+                }];
+                let mut next: u32 = 0;
+                for range in ranges {
+                    let (mut start, mut remaining) = (range.start, range.count);
+                    while remaining > 0 {
+                        if next == own {
+                            next += 1;
+                            continue;
+                        }
+                        // Stop short of the inside id that is already taken
+                        let count = if next < own {
+                            remaining.min(own - next)
+                        } else {
+                            remaining.min(u32::MAX - next)
+                        };
+                        if count == 0 {
+                            return maps;
+                        }
+                        maps.push($struct {
+                            inside_id: $id::from_raw(next),
+                            outside_id: $id::from_raw(start),
+                            count,
+                        });
+                        next += count;
+                        start += count;
+                        remaining -= count;
+                    }
+                }
+                maps
+            }
+        }
+
+        impl fmt::Display for $struct {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(
+                    f,
                     "{inside_id} {outside_id} {count}",
                     inside_id = self.inside_id,
                     outside_id = self.outside_id,
@@ -48,9 +134,225 @@ id_map!(Gid);
 
 pub fn read_uid_map() -> Result<Vec<UidMap>, std::io::Error> {
     let uid_map_file = PathBuf::from("/proc/self/uid_map");
-    let uidmap = std::fs::read_to_string(uid_map_file)?
+    std::fs::read_to_string(uid_map_file)?
         .lines()
-        .map(|x| UidMap::from_str(x).unwrap())
-        .collect();
-    Ok(uidmap)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            UidMap::from_str(line)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+/// Whether maps is the uid map of the initial user namespace, which maps every id to itself
+pub fn is_initial_uid_map(maps: &[UidMap]) -> bool {
+    matches!(
+        maps,
+        [UidMap { inside_id, outside_id, count: u32::MAX }]
+            if inside_id.is_root() && outside_id.is_root()
+    )
+}
+
+/// A range of subordinate ids allocated to a user, see subuid(5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubidRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// Read the ranges allocated to the user given by name or id from /etc/subuid or /etc/subgid.
+/// A missing file means no ranges, and malformed lines are ignored.
+pub fn read_subid_ranges(
+    path: &Path,
+    name: Option<&str>,
+    id: u32,
+) -> Result<Vec<SubidRange>, io::Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    Ok(parse_subid_ranges(&contents, name, id))
+}
+
+fn parse_subid_ranges(contents: &str, name: Option<&str>, id: u32) -> Vec<SubidRange> {
+    let id = id.to_string();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split(':');
+            let owner = fields.next()?;
+            if owner != id && Some(owner) != name {
+                return None;
+            }
+            let start = fields.next()?.parse().ok()?;
+            let count = fields.next()?.parse().ok()?;
+            Some(SubidRange { start, count })
+        })
+        .filter(|range| range.count > 0)
+        .collect()
+}
+
+/// Find program, e.g. newuidmap, in the host's PATH
+pub fn find_helper(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Fork a child that stays outside of the user namespace about to be created, and runs
+/// program (newuidmap or newgidmap) to write maps for pid once the write end of gate,
+/// a pipe from [`gate`], is closed by every other process.
+pub fn spawn_helper(
+    program: &Path,
+    pid: Pid,
+    maps: &[String],
+    gate: (RawFd, RawFd),
+) -> nix::Result<Pid> {
+    // Everything is allocated before forking, the child only reads and execs
+    let to_cstring = |s: &[u8]| CString::new(s).map_err(|_| nix::errno::Errno::EINVAL);
+    let mut args = vec![
+        to_cstring(program.as_os_str().as_bytes())?,
+        to_cstring(pid.to_string().as_bytes())?,
+    ];
+    for map in maps {
+        for field in map.split_whitespace() {
+            args.push(to_cstring(field.as_bytes())?);
+        }
+    }
+    let program = args[0].clone();
+
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => Ok(child),
+        ForkResult::Child => {
+            let (gate_read, gate_write) = gate;
+            let _ = close(gate_write);
+            let mut buf = [0u8];
+            while let Err(nix::errno::Errno::EINTR) = read(gate_read, &mut buf) {}
+            let _ = execv(&program, &args);
+            unsafe { nix::libc::_exit(127) }
+        }
+    }
+}
+
+/// Wait for a helper from [`spawn_helper`], failing unless it succeeded
+pub fn wait_helper(pid: Pid) -> Result<(), io::Error> {
+    match waitpid(pid, None)? {
+        WaitStatus::Exited(_, 0) => Ok(()),
+        status => Err(io::Error::other(format!(
+            "id map helper failed: {status:?}"
+        ))),
+    }
+}
+
+/// A pipe to hold back the helpers from [`spawn_helper`] until the user namespace exists
+pub fn gate() -> nix::Result<(RawFd, RawFd)> {
+    pipe2(OFlag::O_CLOEXEC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_id_map_lines() {
+        let map = UidMap::from_str("         0       1000          1  ").unwrap();
+        assert_eq!(map.inside_id, Uid::from_raw(0));
+        assert_eq!(map.outside_id, Uid::from_raw(1000));
+        assert_eq!(map.count, 1);
+    }
+
+    #[test]
+    fn id_maps_round_trip() {
+        for (inside, outside, count) in [
+            (0, 0, 1),
+            (1000, 1000, 1),
+            (0, 100000, 65536),
+            (1, 4294967294, 1),
+        ] {
+            let map = UidMap {
+                inside_id: Uid::from_raw(inside),
+                outside_id: Uid::from_raw(outside),
+                count,
+            };
+            assert_eq!(map.to_string(), format!("{inside} {outside} {count}"));
+            assert_eq!(UidMap::from_str(&map.to_string()).unwrap(), map);
+
+            let map = GidMap {
+                inside_id: Gid::from_raw(inside),
+                outside_id: Gid::from_raw(outside),
+                count,
+            };
+            assert_eq!(GidMap::from_str(&map.to_string()).unwrap(), map);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_id_map_lines() {
+        assert_eq!(
+            UidMap::from_str("").unwrap_err(),
+            IdMapError::MissingField("inside id")
+        );
+        assert_eq!(
+            GidMap::from_str("0 1000").unwrap_err(),
+            IdMapError::MissingField("count")
+        );
+        assert!(matches!(
+            UidMap::from_str("0 x 1"),
+            Err(IdMapError::InvalidNumber(_))
+        ));
+    }
+
+    #[test]
+    fn recognizes_initial_uid_map() {
+        let parse = |contents: &str| {
+            contents
+                .lines()
+                .map(|line| UidMap::from_str(line).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert!(is_initial_uid_map(&parse("0 0 4294967295")));
+        assert!(!is_initial_uid_map(&parse("0 1000 1")));
+        assert!(!is_initial_uid_map(&parse("0 0 1\n1 100000 65536")));
+        assert!(!is_initial_uid_map(&[]));
+    }
+
+    #[test]
+    fn reads_subid_ranges_by_name_or_id() {
+        let contents = "alice:100000:65536\n1000:300000:10\nbob:200000:65536\nalice:bad:1\n";
+        assert_eq!(
+            parse_subid_ranges(contents, Some("alice"), 1000),
+            [
+                SubidRange {
+                    start: 100000,
+                    count: 65536
+                },
+                SubidRange {
+                    start: 300000,
+                    count: 10
+                },
+            ]
+        );
+        assert_eq!(parse_subid_ranges(contents, None, 1001), []);
+    }
+
+    #[test]
+    fn maps_ranges_around_own_id() {
+        let ranges = [SubidRange {
+            start: 100000,
+            count: 65536,
+        }];
+        let maps: Vec<String> =
+            UidMap::with_ranges(Uid::from_raw(1000), Uid::from_raw(1000), &ranges)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+        assert_eq!(maps, ["1000 1000 1", "0 100000 1000", "1001 101000 64536"]);
+
+        let maps: Vec<String> = GidMap::with_ranges(Gid::from_raw(0), Gid::from_raw(1000), &ranges)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(maps, ["0 1000 1", "1 100000 65536"]);
+    }
 }
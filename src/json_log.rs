@@ -0,0 +1,35 @@
+use std::io::{self, Write};
+
+use env_logger::fmt::Formatter;
+use log::Record;
+
+/// Write a log record as a line of JSON, with its timestamp, level, target and message
+pub fn format(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    writeln!(
+        buf,
+        r#"{{"timestamp":"{}","level":"{}","target":{},"msg":{}}}"#,
+        buf.timestamp(),
+        record.level(),
+        quote(record.target()),
+        quote(&record.args().to_string()),
+    )
+}
+
+/// Quote s as a JSON string
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
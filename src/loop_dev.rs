@@ -0,0 +1,111 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    libc,
+    sys::stat::Mode,
+    unistd::close,
+};
+
+// From linux/loop.h
+const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4C04;
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+/// struct loop_info64
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+/// A read-only loop device backed by a file. The kernel detaches it once it is unmounted,
+/// or when this is dropped if it was never mounted.
+#[derive(Debug)]
+pub struct LoopDevice {
+    pub path: PathBuf,
+    fd: RawFd,
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+/// Whether the file at path starts with the magic number of a squashfs image
+pub fn is_squashfs(path: &Path) -> Result<bool, io::Error> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == b"hsqs"),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Attach file read-only to a free loop device
+pub fn attach(file: &Path) -> Result<LoopDevice, Errno> {
+    let backing = open(file, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    let control = open(
+        "/dev/loop-control",
+        OFlag::O_RDWR | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    );
+    let result = control.and_then(|control| {
+        let result = attach_free(control, backing);
+        let _ = close(control);
+        result
+    });
+    let _ = close(backing);
+    result
+}
+
+fn attach_free(control: RawFd, backing: RawFd) -> Result<LoopDevice, Errno> {
+    loop {
+        let number = Errno::result(unsafe { libc::ioctl(control, LOOP_CTL_GET_FREE as _) })?;
+        let path = PathBuf::from(format!("/dev/loop{number}"));
+        let fd = open(&path, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())?;
+        match Errno::result(unsafe { libc::ioctl(fd, LOOP_SET_FD as _, backing) }) {
+            Ok(_) => {}
+            // Another process took the device first
+            Err(Errno::EBUSY) => {
+                let _ = close(fd);
+                continue;
+            }
+            Err(e) => {
+                let _ = close(fd);
+                return Err(e);
+            }
+        }
+
+        let mut info: LoopInfo64 = unsafe { mem::zeroed() };
+        info.lo_flags = LO_FLAGS_READ_ONLY | LO_FLAGS_AUTOCLEAR;
+        if let Err(e) = Errno::result(unsafe { libc::ioctl(fd, LOOP_SET_STATUS64 as _, &info) }) {
+            unsafe { libc::ioctl(fd, LOOP_CLR_FD as _, 0) };
+            let _ = close(fd);
+            return Err(e);
+        }
+        return Ok(LoopDevice { path, fd });
+    }
+}
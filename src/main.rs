@@ -1,321 +1,3503 @@
 use std::{
+    cell::Cell,
+    collections::HashMap,
     env,
-    ffi::CString,
-    fs,
-    path::{Path, PathBuf},
-    sync::mpsc,
+    ffi::{CString, OsStr, OsString},
+    fmt::Display,
+    fs::{self, File},
+    io::{Read, Write},
+    num::NonZeroUsize,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        },
+    },
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
-use log::{debug, error, info, warn};
+use clap::{CommandFactory, Parser, ValueEnum};
+use log::{debug, error, info, log, warn, Level, LevelFilter};
 
 use nix::{
-    fcntl::{open, OFlag},
-    mount::{mount, MsFlags},
+    errno::Errno,
+    mount::{mount, umount2, MntFlags, MsFlags},
     sched::{unshare, CloneFlags},
-    sys::stat::Mode,
-    unistd::{chroot, close, execve, Gid, Uid},
+    sys::{
+        signal::{kill, Signal},
+        stat::{umask, Mode},
+        statfs::{statfs, CGROUP2_SUPER_MAGIC},
+        statvfs::statvfs,
+        utsname::uname,
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{
+        chroot, close, execve, fork, getpid, isatty, sethostname, unlinkat, ForkResult, Gid, Group,
+        Pid, Uid, UnlinkatFlags, User,
+    },
 };
 
+mod caps;
+mod config;
+mod daemon;
+mod disk;
+mod environ;
+mod gc_root;
 mod id_map;
+mod json_log;
+mod loop_dev;
+mod mountinfo;
+mod net;
+mod oom;
+mod preflight;
+mod probe;
+mod shell_words;
+mod signals;
+mod snapshot;
+mod store;
+use caps::Capability;
 use id_map::*;
 
 #[derive(Parser, Debug)]
 #[command(author, about)]
 struct Cli {
     #[arg(long)]
-    bind: Option<Vec<PathBuf>>,
+    bind: Option<Vec<Bind>>,
+    /// Like --bind, but skip PATH quietly if it doesn't exist
+    #[arg(long, value_name = "PATH")]
+    bind_try: Vec<Bind>,
     #[arg(long)]
     nix_dir: Option<PathBuf>,
     #[arg(long)]
     entrypoint: Option<PathBuf>,
     #[arg(long)]
     mount_dir: Option<PathBuf>,
+    /// Create the directory given with --mount-dir if it doesn't exist, and remove it
+    /// again once the app has exited
+    #[arg(long)]
+    mkdir: bool,
+    /// Read settings from PATH instead of apprun.toml next to AppRun
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Log more, repeat for even more: info, debug, then trace [default: warn]
+    #[arg(long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only log errors
+    #[arg(long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Format of AppRun's log lines
+    #[arg(long, value_enum, env = "APPRUN_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Print the version of AppRun and exit
     #[arg(long)]
     version: bool,
-    #[arg(long, default_value_t = 5.0)]
+    /// Give up on a path that takes longer than SECONDS to check [default: 5]
+    #[arg(long, value_name = "SECONDS")]
+    mount_timeout: Option<f32>,
+    /// Use SECONDS instead of --mount-timeout for PATH and the paths below it
+    #[arg(long, value_name = "PATH=SECONDS", value_parser = parse_bind_timeout)]
+    bind_timeout: Vec<(PathBuf, f32)>,
+    /// Keep setgroups(2) allowed inside the user namespace
+    #[arg(long)]
+    allow_setgroups: bool,
+    /// Map the current user to root inside the user namespace
+    #[arg(long)]
+    map_root: bool,
+    /// Create a symlink NAME inside the container pointing at the bundled store path TARGET
+    #[arg(long, value_name = "NAME:TARGET")]
+    profile_link: Vec<ProfileLink>,
+    /// Bind SRC to DST inside the container only if CONDITION exists on the host
+    #[arg(long, value_name = "CONDITION:SRC:DST")]
+    bind_if: Vec<BindIf>,
+    /// Bind where the device with the label NAME or UUID ID is mounted to DST inside the
+    /// container, skipping it quietly if the device isn't there or isn't mounted
+    #[arg(long, value_name = "LABEL=NAME:DST|UUID=ID:DST")]
+    bind_try_label: Vec<LabelBind>,
+    /// Look up devices by label and UUID in PATH instead of /dev/disk
+    #[arg(long, value_name = "PATH", default_value = "/dev/disk")]
+    disk_dir: PathBuf,
+    /// Write the environment passed to the entrypoint to PATH
+    #[arg(long, value_name = "PATH")]
+    dump_env: Option<PathBuf>,
+    /// Use the environment written by --dump-env to PATH as the base environment
+    #[arg(long, value_name = "PATH")]
+    env_from_dump: Option<PathBuf>,
+    /// Use the host's /nix/store when the bundle doesn't have one
+    #[arg(long)]
+    merge_host_store: bool,
+    /// Reuse the paths of the host's /nix/store, and add only the bundled paths it lacks
+    #[arg(long)]
+    merge_host_nix: bool,
+    /// Stack the nix directory or squashfs image LAYER over the bundled /nix as a read-only
+    /// overlay, each layer over the ones given before it
+    #[arg(
+        long,
+        value_name = "LAYER",
+        conflicts_with_all = ["merge_host_nix", "probe_store"]
+    )]
+    nix_layer: Vec<PathBuf>,
+    /// Fail instead of creating more than N bind mounts
+    #[arg(long, value_name = "N")]
+    limit_mounts: Option<usize>,
+    /// Check that the store paths referenced by the entrypoint exist before launching
+    #[arg(long)]
+    probe_store: bool,
+    /// Only log skipped binds at debug level
+    #[arg(long)]
+    quiet_skips: bool,
+    /// Create a UTS namespace with a hostname derived from the bundled entrypoint,
+    /// unless one is given with --hostname
+    #[arg(long)]
+    hostname_from_bundle: bool,
+    /// Create a UTS namespace, with the hostname `appimage` unless another is given
+    #[arg(long)]
+    unshare_uts: bool,
+    /// Create a UTS namespace with hostname NAME
+    #[arg(long, value_name = "NAME", value_parser = parse_hostname)]
+    hostname: Option<String>,
+    /// Give up resolving the entrypoint after following N symlinks
+    #[arg(long, value_name = "N", default_value_t = 40)]
+    max_symlink_depth: usize,
+    /// Bind the host's kernel modules for the running kernel read-only
+    #[arg(long)]
+    host_modules: bool,
+    /// Bind the host's firmware read-only
+    #[arg(long)]
+    host_firmware: bool,
+    /// Working directory inside the container
+    #[arg(long, value_enum, default_value_t = CwdMode::PreserveOrRoot)]
+    cwd_mode: CwdMode,
+    /// Change to PATH inside the container instead, failing if it doesn't exist
+    #[arg(long, value_name = "PATH", conflicts_with = "cwd_mode")]
+    chdir: Option<PathBuf>,
+    /// Log every namespace, mount, root switch and exec operation with its arguments and result
+    #[arg(long)]
+    audit: bool,
+    /// Print the resolved paths, binds, timeouts and namespaces as JSON and exit
+    #[arg(long)]
+    print_config: bool,
+    /// Log the namespaces and mounts that would be set up, without setting them up or starting the entrypoint
+    #[arg(long)]
+    dry_run: bool,
+    /// Register the entrypoint's store path as a GC root of the host nix while running
+    #[arg(long)]
+    register_gc_root: bool,
+    /// Make every bind of the host's root directories read-only
+    #[arg(long)]
+    readonly_bind_all: bool,
+    /// Write a listing of the assembled root to PATH instead of running the entrypoint
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<PathBuf>,
+    /// Only list the snapshot N directories deep
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    snapshot_depth: usize,
+    /// Label identifying the bundled store's mount in the audit log
+    #[arg(long, value_name = "LABEL", default_value = "bundle-store")]
+    store_label: String,
+    /// Pass VALUE to the entrypoint before the arguments given to AppRun
+    #[arg(long, value_name = "VALUE")]
+    entrypoint_arg: Vec<String>,
+    /// Run COMMAND, split into words like sh(1) does but without expansions, instead of the entrypoint
+    #[arg(long, value_name = "COMMAND", conflicts_with = "entrypoint")]
+    entrypoint_shell_words: Option<String>,
+    /// Don't remount / as rslave, for when mount propagation is already arranged
+    #[arg(long)]
+    no_slave_root: bool,
+    /// Mount a fresh /proc with hidepid=2
+    #[arg(long)]
+    proc_hidepid: bool,
+    /// Mask sensitive files in /proc and make /proc/sys read-only
+    #[arg(long)]
+    proc_mask: bool,
+    /// Keep the host's /sys and /proc writable inside the container
+    #[arg(long)]
+    rw_sys: bool,
+    /// Ignore setuid and setgid bits of files from the host
+    #[arg(long)]
+    nosuid: bool,
+    /// Deny access to device nodes from the host outside of /dev
+    #[arg(long)]
+    nodev: bool,
+    /// Copy the entrypoint into the container instead of running it from the AppDir
+    #[arg(long)]
+    copy_entrypoint: bool,
+    /// TERM to use when the host has none and AppRun runs in a terminal
+    #[arg(long, value_name = "VALUE", default_value = "xterm-256color")]
+    term: String,
+    /// Mount a private tmpfs at /run/user/<uid> and set XDG_RUNTIME_DIR to it
+    #[arg(long)]
+    xdg_runtime: bool,
+    /// Bind the host's XDG_RUNTIME_DIR, /run/dbus and X11 socket directory, and pass on
+    /// DISPLAY, WAYLAND_DISPLAY, XDG_RUNTIME_DIR and DBUS_SESSION_BUS_ADDRESS, for GUI apps
+    #[arg(long, conflicts_with = "xdg_runtime")]
+    desktop: bool,
+    /// Detach from the terminal and run in the background, printing the PID of the daemon
+    #[arg(long)]
+    daemonize: bool,
+    /// Set the umask to MASK, an octal number, before running the entrypoint [default with --daemonize: 022]
+    #[arg(long, value_name = "MASK", value_parser = parse_umask)]
+    umask: Option<Mode>,
+    /// Fail instead of warning if any mount in the container propagates to the host
+    #[arg(long)]
+    verify_propagation: bool,
+    /// Run command NAME, searched for in the PATH inside the container, instead of the entrypoint
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["entrypoint", "entrypoint_shell_words"])]
+    exec_search: Option<PathBuf>,
+    /// Write launch timing and bind metrics in the Prometheus text format to PATH
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+    /// Replace AppRun with the entrypoint, instead of running it as a child and waiting for it
+    #[arg(long)]
+    exec_replace: bool,
+    /// Also pass SIG, one of SIGHUP, SIGUSR1 and SIGUSR2, on to the entrypoint when AppRun
+    /// receives it, e.g. to make a daemon reload
+    #[arg(long, value_name = "SIG", value_parser = parse_forward_signal, conflicts_with = "exec_replace")]
+    forward_signal: Vec<Signal>,
+    /// Once the entrypoint has exited, wait up to SECONDS for the processes it started to
+    /// exit too, before exiting with the entrypoint's exit code
+    #[arg(long, value_name = "SECONDS", conflicts_with = "exec_replace")]
+    wait_children: Option<f32>,
+    /// Tell when the OOM killer killed the entrypoint, e.g. at the memory limit of its cgroup,
+    /// and exit with 251 then
+    #[arg(long, conflicts_with = "exec_replace")]
+    signal_on_oom: bool,
+    /// Don't pass the host's environment on to the entrypoint
+    #[arg(long)]
+    clearenv: bool,
+    /// Set KEY to VALUE in the entrypoint's environment, overriding inherited values
+    #[arg(long, value_name = "KEY=VALUE")]
+    setenv: Vec<String>,
+    /// Remove KEY from the entrypoint's environment
+    #[arg(long, value_name = "KEY")]
+    unsetenv: Vec<String>,
+    /// Use chroot(2) instead of pivot_root(2) to enter the container
+    #[arg(long)]
+    use_chroot: bool,
+    /// Run the entrypoint as PID 1 of a new PID namespace, with its own /proc
+    #[arg(long, conflicts_with = "exec_replace")]
+    unshare_pid: bool,
+    /// Run the entrypoint in a new network namespace with only a loopback interface
+    #[arg(long)]
+    unshare_net: bool,
+    /// Run the entrypoint in a new IPC namespace, with its own /dev/shm and /dev/mqueue
+    #[arg(long)]
+    unshare_ipc: bool,
+    /// Run the entrypoint in a new cgroup namespace, with its own /sys/fs/cgroup
+    #[arg(long)]
+    unshare_cgroup: bool,
+    /// Create the namespaces in LIST, separated by commas, besides the mount namespace
+    #[arg(long, value_name = "LIST", value_enum, value_delimiter = ',')]
+    unshare: Vec<Namespace>,
+    /// Link the namespaces of the app from directory PATH while it runs, for entering
+    /// them with e.g. nsenter --mount=PATH/mnt
+    #[arg(long, value_name = "PATH")]
+    ns_fd_path: Option<PathBuf>,
+    /// Write the PID of the entrypoint to PATH, and remove it again once it has exited
+    #[arg(long, value_name = "PATH")]
+    pidfile: Option<PathBuf>,
+    /// Set up a /dev with only common device nodes instead of binding the host's
+    #[arg(long)]
+    minimal_dev: bool,
+    /// Bind PATH read-only, whether it is one of the bound root directories or below one
+    #[arg(long, value_name = "PATH")]
+    ro_bind: Vec<PathBuf>,
+    /// Copy the host file PATH into the container at startup, instead of binding it
+    #[arg(long, value_name = "PATH")]
+    copy: Vec<PathBuf>,
+    /// Replace /etc/passwd and /etc/group with ones describing the current user inside
+    /// the container
+    #[arg(long)]
+    fake_passwd: bool,
+    /// Drop all capabilities of the entrypoint, including from the bounding set, so that it
+    /// can't regain them, e.g. when started as root
+    #[arg(long)]
+    drop_caps: bool,
+    /// Keep CAP, e.g. CAP_NET_RAW, when dropping capabilities
+    #[arg(long, value_name = "CAP", requires = "drop_caps")]
+    cap_add: Vec<Capability>,
+    /// Leave the top-level path PATH, e.g. /media, out of the host's root directories
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = parse_exclude,
+        conflicts_with_all = ["bind", "bind_try"]
+    )]
+    exclude: Vec<OsString>,
+    /// Warn about host paths, /dev nodes by default, that can't be opened inside the container
+    #[arg(long)]
+    preflight: bool,
+    /// Check PATH with --preflight instead of the default paths
+    #[arg(long, value_name = "PATH", requires = "preflight")]
+    preflight_path: Vec<PathBuf>,
+    /// Mount a private tmpfs at /tmp instead of the host's /tmp
+    #[arg(long)]
+    private_tmp: bool,
+    /// Limit the tmpfs at the container root, and the one at /tmp, to SIZE, e.g. 512M or 2G
+    #[arg(long, value_name = "SIZE", value_parser = parse_tmpfs_size)]
+    tmpfs_size: Option<String>,
+}
+
+fn parse_umask(s: &str) -> Result<Mode, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .and_then(Mode::from_bits)
+        .ok_or_else(|| format!("expected an octal file mode, got {s:?}"))
+}
+
+/// The kernel's limit on the length of a hostname, HOST_NAME_MAX
+const MAX_HOSTNAME_LEN: usize = 64;
+
+/// Check that a hostname fits into the kernel's limit
+fn parse_hostname(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > MAX_HOSTNAME_LEN {
+        Err(format!(
+            "expected a hostname of 1 to {MAX_HOSTNAME_LEN} bytes, got {s:?}"
+        ))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+/// Check that an excluded path is a top-level path, like /media or media, and keep its name
+fn parse_exclude(s: &str) -> Result<OsString, String> {
+    let mut components = Path::new(s).components().peekable();
+    components.next_if_eq(&Component::RootDir);
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Ok(name.to_os_string()),
+        _ => Err(format!(
+            "expected a top-level path like /media or media, got {s:?}"
+        )),
+    }
+}
+
+/// Check a size option of tmpfs(5): a number of bytes with an optional k, m or g suffix,
+/// or a percentage of RAM
+fn parse_tmpfs_size(s: &str) -> Result<String, String> {
+    let digits = s.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+    let suffix_len = s.len() - digits.len();
+    if suffix_len <= 1 && !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        Ok(s.to_string())
+    } else {
+        Err(format!("expected a size like 512M, 2G or 50%, got {s:?}"))
+    }
+}
+
+fn parse_forward_signal(s: &str) -> Result<Signal, String> {
+    let upper = s.to_ascii_uppercase();
+    let name = if upper.starts_with("SIG") {
+        upper
+    } else {
+        format!("SIG{upper}")
+    };
+    match Signal::from_str(&name) {
+        Ok(signal @ (Signal::SIGHUP | Signal::SIGUSR1 | Signal::SIGUSR2)) => Ok(signal),
+        _ => Err(format!("expected SIGHUP, SIGUSR1 or SIGUSR2, got {s:?}")),
+    }
+}
+
+fn parse_bind_timeout(s: &str) -> Result<(PathBuf, f32), String> {
+    let (path, seconds) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATH=SECONDS, got {s:?}"))?;
+    match seconds.parse::<f32>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok((PathBuf::from(path), seconds)),
+        _ => Err(format!("expected a number of seconds, got {seconds:?}")),
+    }
+}
+
+/// How to choose the working directory inside the container
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum CwdMode {
+    /// Keep the working directory, failing if it isn't visible inside the container
+    Preserve,
+    /// Keep the working directory if it is visible inside the container, otherwise use /
+    #[default]
+    PreserveOrRoot,
+    /// Change to /
+    Root,
+    /// Change to the directory containing AppRun
+    App,
+    /// Bind the working directory into the container if needed, and keep it
+    Bind,
+}
+
+/// How log lines are written to stderr
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with timestamp, level, target and msg
+    Json,
+}
+
+/// A namespace that --unshare can create
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Namespace {
+    /// The mount namespace, which is always created
+    Mount,
+    /// Like --unshare-pid
+    Pid,
+    /// Like --unshare-net
+    Net,
+    /// Like --unshare-uts
+    Uts,
+    /// System V IPC objects and POSIX message queues
+    Ipc,
+    /// The view of the cgroup hierarchy
+    Cgroup,
+}
+
+/// Directories created on the host, removed again once the app has exited. The parent is
+/// kept open, since the host's root is out of reach once the root was pivoted.
+#[derive(Debug)]
+struct CreatedDir {
+    /// The directory the outermost created directory is in
+    parent: fs::File,
+    /// The innermost created directory, relative to parent
+    relative: PathBuf,
+}
+
+impl CreatedDir {
+    /// Create path and any missing parents
+    fn create(path: &Path) -> Result<CreatedDir, std::io::Error> {
+        // The outermost missing directory; / always exists, so it has a parent
+        let outermost = path
+            .ancestors()
+            .take_while(|ancestor| !ancestor.exists())
+            .last()
+            .unwrap_or(path);
+        let parent = outermost.parent().unwrap_or(Path::new("/"));
+        fs::create_dir_all(path)?;
+        Ok(CreatedDir {
+            parent: fs::File::open(parent)?,
+            relative: path.strip_prefix(parent).unwrap().to_path_buf(),
+        })
+    }
+}
+
+/// The links to namespaces published by --ns-fd-path, with the name of the link, and of
+/// the namespace in /proc/PID/ns. The PID namespace is only entered by the children of
+/// the process that created it.
+const NS_LINKS: [(CloneFlags, &str, &str); 7] = [
+    (CloneFlags::CLONE_NEWNS, "mnt", "mnt"),
+    (CloneFlags::CLONE_NEWUSER, "user", "user"),
+    (CloneFlags::CLONE_NEWPID, "pid", "pid_for_children"),
+    (CloneFlags::CLONE_NEWNET, "net", "net"),
+    (CloneFlags::CLONE_NEWUTS, "uts", "uts"),
+    (CloneFlags::CLONE_NEWIPC, "ipc", "ipc"),
+    (CloneFlags::CLONE_NEWCGROUP, "cgroup", "cgroup"),
+];
+
+/// Links written by --ns-fd-path, removed again once the app has exited
+#[derive(Debug)]
+struct NsLinks {
+    /// The directory the links are in
+    dir: fs::File,
+    names: Vec<&'static str>,
+    /// The directories created for the links, if it didn't exist
+    created: Option<CreatedDir>,
+}
+
+/// The file given with --pidfile, opened along with its directory before entering the
+/// container, since the host's root is out of reach afterwards
+#[derive(Debug)]
+struct PidFile {
+    dir: fs::File,
+    name: OsString,
+    file: fs::File,
+}
+
+impl PidFile {
+    /// Create or truncate the file at path
+    fn create(path: &Path) -> Result<PidFile, std::io::Error> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("pidfile {path:?} is not a file path"),
+            ));
+        };
+        Ok(PidFile {
+            dir: fs::File::open(parent)?,
+            name: name.to_os_string(),
+            file: fs::File::create(path)?,
+        })
+    }
+
+    fn write(&self, pid: Pid) -> Result<(), std::io::Error> {
+        (&self.file).write_all(format!("{pid}\n").as_bytes())
+    }
+}
+
+/// A symlink created inside the container, pointing into the bundled nix store
+#[derive(Debug, Clone)]
+struct ProfileLink {
+    name: PathBuf,
+    target: PathBuf,
+}
+
+impl FromStr for ProfileLink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, target) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected NAME:TARGET, got {s:?}"))?;
+        let (name, target) = (PathBuf::from(name), PathBuf::from(target));
+        if mount_path_of(Path::new("/"), &name).is_none() {
+            return Err(format!(
+                "link name {name:?} must be an absolute path below /"
+            ));
+        }
+        if !target.starts_with("/nix/store") || target.parent() == Some(Path::new("/nix")) {
+            return Err(format!(
+                "link target {target:?} must be a path in /nix/store"
+            ));
+        }
+        Ok(ProfileLink { name, target })
+    }
+}
+
+#[derive(Debug, Default)]
+struct AppRun {
+    binds: Option<Vec<Bind>>,
+    /// Binds that are skipped when missing, unlike binds
+    bind_tries: Vec<Bind>,
+    nix_dir: PathBuf,
+    mount_dir: PathBuf,
+    /// Whether mount_dir was given with --mount-dir rather than picked by AppRun
+    explicit_mount_dir: bool,
+    /// The directories --mkdir created for mount_dir, removed again by cleanup
+    created_mount_dir: Option<CreatedDir>,
+    entrypoint: PathBuf,
+    args: Vec<String>,
+    new_user_namespace: bool,
     mount_timeout: f32,
+    /// Timeouts overriding mount_timeout for paths and the paths below them
+    bind_timeouts: HashMap<PathBuf, f32>,
+    allow_setgroups: bool,
+    map_root: bool,
+    profile_links: Vec<ProfileLink>,
+    bind_ifs: Vec<BindIf>,
+    /// Binds of devices by label or UUID, skipped when the device isn't mounted
+    label_binds: Vec<LabelBind>,
+    /// Where the links to devices by label and UUID are
+    disk_dir: PathBuf,
+    dump_env: Option<PathBuf>,
+    env_from_dump: Option<PathBuf>,
+    merge_host_store: bool,
+    merge_host_nix: bool,
+    /// Directories or squashfs images stacked over the nix directory, the uppermost last
+    nix_layers: Vec<PathBuf>,
+    limit_mounts: Option<usize>,
+    quiet_skips: bool,
+    hostname_from_bundle: bool,
+    unshare_uts: bool,
+    hostname: Option<String>,
+    max_symlink_depth: usize,
+    host_modules: bool,
+    host_firmware: bool,
+    cwd_mode: CwdMode,
+    chdir: Option<PathBuf>,
+    audit: bool,
+    dry_run: bool,
+    register_gc_root: bool,
+    readonly_bind_all: bool,
+    snapshot: Option<PathBuf>,
+    snapshot_depth: usize,
+    store_label: String,
+    no_slave_root: bool,
+    proc_hidepid: bool,
+    proc_mask: bool,
+    rw_sys: bool,
+    nosuid: bool,
+    nodev: bool,
+    copy_entrypoint: bool,
+    term: String,
+    xdg_runtime: bool,
+    desktop: bool,
+    verify_propagation: bool,
+    /// Whether the entrypoint is a command to search for in PATH
+    exec_search: bool,
+    metrics_file: Option<PathBuf>,
+    exec_replace: bool,
+    /// Signals passed on to the entrypoint besides the terminating ones
+    forward_signals: Vec<Signal>,
+    /// How long to wait for the descendants of the entrypoint after it has exited
+    wait_children: Option<Duration>,
+    signal_on_oom: bool,
+    /// Mounts from the config file, made after all others
+    extra_mounts: Vec<config::Mount>,
+    clearenv: bool,
+    setenv: Vec<String>,
+    unsetenv: Vec<String>,
+    use_chroot: bool,
+    unshare_pid: bool,
+    unshare_net: bool,
+    unshare_ipc: bool,
+    unshare_cgroup: bool,
+    minimal_dev: bool,
+    ro_binds: Vec<PathBuf>,
+    /// Host files to copy into the container instead of binding them
+    copies: Vec<PathBuf>,
+    fake_passwd: bool,
+    /// The only capabilities the entrypoint keeps, if they are to be dropped
+    keep_caps: Option<Vec<Capability>>,
+    /// File names of the top-level paths to leave out
+    excludes: Vec<OsString>,
+    private_tmp: bool,
+    tmpfs_size: Option<String>,
+    /// Paths to check after setting up the container, if any
+    preflight_paths: Vec<PathBuf>,
+    /// Where to link the namespaces of the app from
+    ns_fd_path: Option<PathBuf>,
+    ns_links: Option<NsLinks>,
+    pidfile: Option<PathBuf>,
+    /// The opened pidfile, written once the PID of the entrypoint is known
+    opened_pidfile: Option<PidFile>,
+    /// When AppRun started
+    started: Option<Instant>,
+    /// Directory containing AppRun
+    app_dir: PathBuf,
+    /// Number of bind mounts created so far
+    bind_count: Cell<usize>,
+    /// Number of bind mounts that failed so far
+    failed_bind_count: Cell<usize>,
+    prober: probe::Prober,
+}
+
+/// Find a command in the PATH of env, or of AppRun if env has none, like a shell does.
+/// This runs after chrooting, so the directories are searched in the container.
+fn search_path(name: &Path, env: &[CString]) -> Result<PathBuf, std::io::Error> {
+    const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+    if name.components().count() > 1 {
+        return Ok(name.to_path_buf());
+    }
+    let path = env
+        .iter()
+        .find_map(|var| var.to_str().ok()?.strip_prefix("PATH="))
+        .map(str::to_string)
+        .or_else(|| env::var("PATH").ok())
+        .unwrap_or_else(|| DEFAULT_PATH.to_string());
+
+    for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+        let candidate = Path::new(dir).join(name);
+        if is_executable(&candidate) {
+            debug!("Found {name:?} at {candidate:?}");
+            return Ok(candidate);
+        }
+    }
+
+    error!("{name:?}: command not found in container PATH (searched {path})");
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{name:?}: command not found in container PATH {path:?}"),
+    ))
+}
+
+/// Where the container path path is under mount_dir, or None if path doesn't name
+/// anything below the root, like `/`, `/foo/..` or a relative path
+fn mount_path_of(mount_dir: &Path, path: &Path) -> Option<PathBuf> {
+    let mut components = path.components();
+    if components.next() != Some(Component::RootDir) {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            // .. could climb out of mount_dir
+            _ => return None,
+        }
+    }
+    (!relative.as_os_str().is_empty()).then(|| mount_dir.join(relative))
+}
+
+/// Whether path is a file with an exec bit set
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// The interpreter named in the `#!` line of a script, or None if path isn't a script
+fn script_interpreter(path: &Path) -> Result<Option<PathBuf>, std::io::Error> {
+    // The kernel only looks at this many bytes, see binfmt_script
+    const MAX_LEN: u64 = 256;
+
+    let mut head = vec![];
+    fs::File::open(path)?.take(MAX_LEN).read_to_end(&mut head)?;
+    let Some(line) = head.strip_prefix(b"#!") else {
+        return Ok(None);
+    };
+    let line = line.split(|&c| c == b'\n').next().unwrap_or_default();
+    Ok(line
+        .split(|c| c.is_ascii_whitespace())
+        .find(|word| !word.is_empty())
+        .map(|word| PathBuf::from(OsStr::from_bytes(word))))
+}
+
+/// Print a line of the audit log. It is written at once,
+/// so that the lines of AppRun and its child don't get mixed up.
+fn audit_log(line: &str) {
+    let _ = std::io::stderr().write_all(format!("[apprun-audit] {line}\n").as_bytes());
+}
+
+/// Wait for a child to exit, returning its exit code like a shell does,
+/// i.e. 128 plus the signal number if it was killed by a signal
+fn wait_for_exit(child: Pid) -> Result<i32, Errno> {
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+            // Keep waiting, however many signals arrive while the child runs
+            Ok(_) | Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Exit code for an entrypoint killed by the OOM killer, with --signal-on-oom
+const OOM_EXIT_CODE: i32 = 251;
+
+/// Tell whether the OOM killer killed processes since the count was before, and return the
+/// exit code to use instead of code, the one of the entrypoint
+fn report_oom_kills(code: i32, before: u64, counter: &oom::OomKills) -> i32 {
+    let kills = match counter.count() {
+        Ok(count) => count.saturating_sub(before),
+        Err(e) => {
+            warn!("Cannot count OOM kills: {e}");
+            return code;
+        }
+    };
+    if kills == 0 {
+        code
+    } else if code == 128 + Signal::SIGKILL as i32 {
+        error!("The app was killed by the OOM killer, it ran out of memory in its cgroup");
+        OOM_EXIT_CODE
+    } else {
+        warn!("The OOM killer killed {kills} processes in the cgroup of the app while it ran");
+        code
+    }
+}
+
+/// Reap the remaining descendants, which are reparented to AppRun as a subreaper,
+/// giving up on those still running after timeout
+fn wait_for_descendants(timeout: Duration) -> Result<(), Errno> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) if Instant::now() >= deadline => {
+                warn!("Descendants of the entrypoint are still running after {timeout:?}, not waiting for them");
+                return Ok(());
+            }
+            Ok(WaitStatus::StillAlive) => thread::sleep(POLL_INTERVAL),
+            Ok(_) | Err(Errno::EINTR) => continue,
+            Err(Errno::ECHILD) => {
+                debug!("All descendants of the entrypoint have exited");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Variables passed on with --desktop, even with --clearenv
+const DESKTOP_ENV: [&str; 4] = [
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XDG_RUNTIME_DIR",
+    "DBUS_SESSION_BUS_ADDRESS",
+];
+
+/// XDG_RUNTIME_DIR inside the container, named after the uid on the host
+fn xdg_runtime_dir(uid: Uid) -> PathBuf {
+    PathBuf::from(format!("/run/user/{uid}"))
+}
+
+/// The uid and gid maps of the user namespace, one line per range
+struct IdMaps {
+    uid: Vec<String>,
+    gid: Vec<String>,
+}
+
+/// newuidmap and newgidmap, forked outside of the user namespace and waiting for it
+struct IdMapHelpers {
+    gate: RawFd,
+    uid: Pid,
+    gid: Pid,
+}
+
+impl IdMapHelpers {
+    /// Stop the helpers without letting them write the maps
+    fn cancel(self) {
+        for pid in [self.uid, self.gid] {
+            let _ = kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+        }
+        let _ = close(self.gate);
+    }
+}
+
+/// A host path bound into the container
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bind {
+    source: PathBuf,
+    dest: PathBuf,
+}
+
+impl Bind {
+    /// Bind a host path at the same location
+    fn same_path(path: PathBuf) -> Self {
+        Bind {
+            source: path.clone(),
+            dest: path,
+        }
+    }
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = split_unescaped(s, ':');
+        // Only the last colon separates the destination
+        let bind = if parts.len() == 1 {
+            Bind::same_path(parts.remove(0).into())
+        } else {
+            let dest = parts.pop().unwrap().into();
+            Bind {
+                source: parts.join(":").into(),
+                dest,
+            }
+        };
+        if mount_path_of(Path::new("/"), &bind.dest).is_none() {
+            return Err(format!(
+                "bind destination {:?} must be an absolute path below /",
+                bind.dest
+            ));
+        }
+        Ok(bind)
+    }
+}
+
+/// A bind mount performed only when a host path exists
+#[derive(Debug, Clone)]
+struct BindIf {
+    condition: PathBuf,
+    source: PathBuf,
+    dest: PathBuf,
+}
+
+impl FromStr for BindIf {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [condition, source, dest]: [String; 3] = split_unescaped(s, ':')
+            .try_into()
+            .map_err(|_| format!("expected CONDITION:SRC:DST, got {s:?}"))?;
+        let (condition, source, dest) = (condition.into(), source.into(), PathBuf::from(dest));
+        if mount_path_of(Path::new("/"), &dest).is_none() {
+            return Err(format!(
+                "bind destination {dest:?} must be an absolute path below /"
+            ));
+        }
+        Ok(BindIf {
+            condition,
+            source,
+            dest,
+        })
+    }
+}
+
+/// A bind of wherever a device, given by label or UUID, is mounted
+#[derive(Debug, Clone)]
+struct LabelBind {
+    tag: disk::Tag,
+    name: String,
+    dest: PathBuf,
+}
+
+impl FromStr for LabelBind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected LABEL=NAME:DST or UUID=ID:DST, got {s:?}");
+        let [device, dest]: [String; 2] =
+            split_unescaped(s, ':').try_into().map_err(|_| invalid())?;
+        let (tag, name) = match device.split_once('=') {
+            Some(("LABEL", name)) => (disk::Tag::Label, name),
+            Some(("UUID", id)) => (disk::Tag::Uuid, id),
+            _ => return Err(invalid()),
+        };
+        if name.is_empty() || name.contains('/') {
+            return Err(invalid());
+        }
+        let dest = PathBuf::from(dest);
+        if mount_path_of(Path::new("/"), &dest).is_none() {
+            return Err(format!(
+                "bind destination {dest:?} must be an absolute path below /"
+            ));
+        }
+        Ok(LabelBind {
+            tag,
+            name: name.to_string(),
+            dest,
+        })
+    }
+}
+
+/// Split a string on a separator, unless it is escaped with a backslash.
+/// `\\` stands for a literal backslash.
+fn split_unescaped(s: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == sep || next == '\\' => parts.last_mut().unwrap().push(next),
+                Some(next) => parts.last_mut().unwrap().extend([c, next]),
+                None => parts.last_mut().unwrap().push(c),
+            },
+            c if c == sep => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// Escape the characters that separate layers and options in overlayfs mount options
+fn overlay_escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        if matches!(c, ':' | ',' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// How often [`retry_on_eintr`] retries a syscall before giving up
+const EINTR_RETRIES: usize = 32;
+
+/// Retry a syscall while it is interrupted by a signal, up to [`EINTR_RETRIES`] times in a
+/// row, so that a steady stream of signals fails the call instead of hanging AppRun.
+/// Not for close(2), which releases the fd even when interrupted.
+fn retry_on_eintr<T>(mut f: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    for _ in 0..EINTR_RETRIES {
+        match f() {
+            Err(Errno::EINTR) => debug!("Interrupted by a signal, retrying"),
+            result => return result,
+        }
+    }
+    warn!("Interrupted by a signal {EINTR_RETRIES} times in a row, giving up");
+    f()
 }
 
-#[derive(Debug, Default)]
-struct AppRun {
-    binds: Option<Vec<PathBuf>>,
-    nix_dir: PathBuf,
-    mount_dir: PathBuf,
-    entrypoint: PathBuf,
-    args: Vec<String>,
-    new_user_namespace: bool,
-    mount_timeout: f32,
-}
+/// Run f, and if enabled, log the call described by call and its result to the audit log
+fn audit<T, E: Display>(
+    enabled: bool,
+    call: impl FnOnce() -> String,
+    f: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    if !enabled {
+        return f();
+    }
+    let call = call();
+    audit_log(&call);
+    let result = f();
+    match &result {
+        Ok(_) => audit_log(&format!("{call} = ok")),
+        Err(e) => audit_log(&format!("{call} = error: {e}")),
+    }
+    result
+}
+
+/// Flags of the recursive bind mounts into the container.
+///
+/// Propagation flags are ignored along with MS_BIND, so the binds get the propagation of
+/// their source: after / was remounted as a slave, they receive mounts from the host, like
+/// a USB stick showing up in /media, but mounts in the container don't propagate back.
+/// See https://www.kernel.org/doc/Documentation/filesystems/sharedsubtree.txt
+fn rec_bind_flags() -> MsFlags {
+    MsFlags::MS_BIND | MsFlags::MS_REC
+}
+
+/// Create the mount point for binding path at mount_path: a directory, or an empty file.
+/// Anything already there is kept, without truncating a file.
+fn create_mount_point(path: &Path, mount_path: &Path) -> Result<(), std::io::Error> {
+    if path.is_dir() {
+        fs::create_dir_all(mount_path)
+    } else {
+        fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(mount_path)
+            .map(drop)
+    }
+}
+
+/// How often [`bind_in_worker`] creates the mount point and binds again after ENOENT
+const BIND_ENOENT_RETRIES: usize = 1;
+
+/// Bind path at mount_path from a worker of [`AppRun::rec_bind_mount_all`].
+/// Failing to create the mount point is an error, while the result of the mount is returned.
+///
+/// Mount points are created while holding create_lock, so that workers don't create the same
+/// parent directories at once. A sibling bind can still mount over a parent between creating
+/// the mount point and mounting, hiding it, so both are retried after ENOENT.
+fn bind_in_worker(
+    path: &Path,
+    mount_path: &Path,
+    create_lock: &Mutex<()>,
+    audit_enabled: bool,
+) -> Result<nix::Result<()>, std::io::Error> {
+    let flags = rec_bind_flags();
+    let mut retries = BIND_ENOENT_RETRIES;
+    loop {
+        let created = {
+            let _guard = create_lock.lock().unwrap();
+            create_mount_point(path, mount_path)
+        };
+        let result = match created {
+            Ok(()) => audit(
+                audit_enabled,
+                || {
+                    format!(
+                        "mount({:?}, {mount_path:?}, None, {flags:?}, None)",
+                        Some(path)
+                    )
+                },
+                || {
+                    retry_on_eintr(|| {
+                        mount(Some(path), mount_path, None::<&str>, flags, None::<&str>)
+                    })
+                },
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && retries > 0 => Err(Errno::ENOENT),
+            Err(e) => return Err(e),
+        };
+        match result {
+            Err(Errno::ENOENT) if retries > 0 => {
+                debug!("{mount_path:?} went missing while binding {path:?}, retrying");
+                retries -= 1;
+            }
+            result => return Ok(result),
+        }
+    }
+}
+
+impl AppRun {
+    /// Execute the entrypoint
+    fn exec_in_chroot(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // These files are host paths, so handle them before chrooting
+        let mut env = match &self.env_from_dump {
+            Some(path) => {
+                info!("Loading environment from {path:?}");
+                environ::read_dump(path)?
+            }
+            None => {
+                let mut env = vec![];
+                if !self.clearenv {
+                    for (key, value) in env::vars_os() {
+                        let mut entry = key.into_vec();
+                        entry.push(b'=');
+                        entry.extend(value.into_vec());
+                        env.push(CString::new(entry)?);
+                    }
+                }
+                if self.desktop {
+                    for key in DESKTOP_ENV {
+                        if let Some(value) = env::var_os(key) {
+                            let mut entry = format!("{key}=").into_bytes();
+                            entry.extend(value.into_vec());
+                            environ::merge(&mut env, vec![CString::new(entry)?]);
+                        }
+                    }
+                }
+                if let Some(term) = self.term() {
+                    environ::merge(&mut env, vec![CString::new(format!("TERM={term}"))?]);
+                }
+                if self.xdg_runtime {
+                    let dir = xdg_runtime_dir(Uid::effective());
+                    let entry = CString::new(format!("XDG_RUNTIME_DIR={}", dir.display()))?;
+                    environ::merge(&mut env, vec![entry]);
+                }
+                // The environment baked into the bundle, e.g. entrypoint.env
+                let mut baked_env = self.entrypoint.clone().into_os_string();
+                baked_env.push(".env");
+                let baked_env = PathBuf::from(baked_env);
+                if !self.exec_search && baked_env.exists() {
+                    info!("Loading environment from {baked_env:?}");
+                    environ::merge(&mut env, environ::read_dump(&baked_env)?);
+                }
+                env
+            }
+        };
+        for name in &self.unsetenv {
+            environ::remove(&mut env, name);
+        }
+        for entry in &self.setenv {
+            if !entry.contains('=') {
+                error!("Invalid --setenv {entry:?}, expected KEY=VALUE");
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("expected KEY=VALUE, got {entry:?}"),
+                )));
+            }
+            environ::merge(&mut env, vec![CString::new(entry.as_str())?]);
+        }
+        if let Some(path) = &self.dump_env {
+            info!("Writing environment to {path:?}");
+            environ::write_dump(path, &env)?;
+        }
+
+        self.check_profile_links()?;
+        // A command searched for in PATH, or a relative path, is only found inside the container
+        if !self.exec_search && self.entrypoint.is_absolute() {
+            self.check_entrypoint()?;
+        }
+
+        if self.register_gc_root && !self.dry_run {
+            self.register_gc_root();
+        }
+
+        // Opened before the namespaces are created, which may hide the cgroup
+        let oom_kills = if self.signal_on_oom {
+            oom::OomKills::open()
+                .and_then(|counter| Ok((counter.count()?, counter)))
+                .inspect_err(|e| warn!("Cannot count OOM kills, not reporting them: {e}"))
+                .ok()
+        } else {
+            None
+        };
+
+        let mount_start = Instant::now();
+        self.mounts()?;
+        let mount_time = mount_start.elapsed();
+        if self.dry_run {
+            info!("Dry run, not starting {:?}", self.entrypoint);
+            return Ok(());
+        }
+        self.check_propagation()?;
+        self.preflight();
+
+        if let Some(path) = &self.snapshot {
+            info!("Writing snapshot of the container root to {path:?}");
+            snapshot::write_listing(&self.mount_dir, path, self.snapshot_depth)?;
+            return Ok(());
+        }
+
+        if self.copy_entrypoint {
+            self.entrypoint = self.copy_entrypoint()?;
+        }
+
+        let launch_time = self.started.map(|t| t.elapsed()).unwrap_or_default();
+        // The metrics file is a host path, which pivot_root(2) hides from AppRun as well,
+        // so open it now. Nothing is left to write them once the entrypoint replaced AppRun.
+        let metrics_file = self.metrics_file.as_ref().and_then(|path| {
+            File::create(path)
+                .inspect_err(|e| warn!("Failed to write metrics to {path:?}: {e}"))
+                .ok()
+        });
+        if self.exec_replace {
+            self.write_metrics(metrics_file.as_ref(), launch_time, mount_time, None);
+        }
+
+        if self.unshare_pid {
+            // No threads can be created after this, so it has to wait until the mounts are done
+            let clone_flags = CloneFlags::CLONE_NEWPID;
+            self.audit(
+                || format!("unshare({clone_flags:?})"),
+                || retry_on_eintr(|| unshare(clone_flags)),
+            )?;
+        }
+        // The links point into AppRun's /proc, which is only valid while it runs
+        if let Some(path) = &self.ns_fd_path {
+            self.ns_links = Some(self.write_ns_links(path)?);
+        }
+        if let Some(path) = &self.pidfile {
+            debug!("Opening pidfile {path:?}");
+            self.opened_pidfile = Some(PidFile::create(path).inspect_err(|e| {
+                error!("Failed to create pidfile {path:?}: {e}");
+            })?);
+        }
+
+        if !self.exec_replace {
+            if self.wait_children.is_some() {
+                // Orphaned descendants of the entrypoint are reparented to AppRun
+                self.audit(
+                    || "prctl(PR_SET_CHILD_SUBREAPER, 1)".to_string(),
+                    || {
+                        Errno::result(unsafe {
+                            nix::libc::prctl(nix::libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0)
+                        })
+                    },
+                )?;
+            }
+            // Run the entrypoint in a child, and exit with its status once it's done.
+            // In a new PID namespace, the child is its PID 1.
+            signals::block(&self.forward_signals)?;
+            if let ForkResult::Parent { child } =
+                self.audit(|| "fork()".to_string(), || unsafe { fork() })?
+            {
+                signals::forward_to(child, self.unshare_pid, &self.forward_signals)?;
+                if let Some(pidfile) = &self.opened_pidfile {
+                    if let Err(e) = pidfile.write(child) {
+                        warn!("Failed to write the PID of the entrypoint to the pidfile: {e}");
+                    }
+                }
+                let code = wait_for_exit(child)?;
+                signals::child_exited();
+                let code = match &oom_kills {
+                    Some((before, counter)) => report_oom_kills(code, *before, counter),
+                    None => code,
+                };
+                if let Some(timeout) = self.wait_children {
+                    wait_for_descendants(timeout)?;
+                }
+                debug!("Entrypoint exited with {code}");
+                self.write_metrics(metrics_file.as_ref(), launch_time, mount_time, Some(code));
+                self.cleanup();
+                std::process::exit(code);
+            }
+            signals::unblock(&self.forward_signals)?;
+        }
+        if self.unshare_pid {
+            // proc shows the PID namespace of the process mounting it
+            self.setup_proc()?;
+        }
+
+        self.chroot()?;
+
+        // Execute a shell
+        // https://stackoverflow.com/questions/38948669/whats-the-most-direct-way-to-convert-a-path-to-a-c-char
+        if self.exec_search {
+            self.entrypoint = search_path(&self.entrypoint, &env)?;
+        }
+        let cmd = CString::new(self.entrypoint.as_os_str().as_bytes())?;
+        // Programs expect at least argv[0], so never exec with an empty argv
+        if self.args.is_empty() {
+            let argv0 = self
+                .entrypoint
+                .file_name()
+                .unwrap_or(self.entrypoint.as_os_str());
+            self.args.push(argv0.to_string_lossy().into_owned());
+        }
+        let args = self
+            .args
+            .iter()
+            .map(|arg| {
+                CString::new(arg.as_str()).map_err(|_| {
+                    error!("Argument {arg:?} for the entrypoint contains a NUL byte");
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("argument {arg:?} contains a NUL byte"),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // Only after the mounts and chroot, which need them
+        if let Some(keep) = &self.keep_caps {
+            info!("Dropping capabilities except {keep:?}");
+            self.audit(
+                || format!("drop capabilities except {keep:?}"),
+                || caps::drop_except(keep),
+            )
+            .inspect_err(|e| error!("Failed to drop capabilities: {e}"))?;
+        }
+        info!("Executing entrypoint with {:?}", args);
+        // The entrypoint replaces AppRun, so it keeps its PID
+        if self.exec_replace {
+            if let Some(pidfile) = &self.opened_pidfile {
+                pidfile.write(getpid())?;
+            }
+        }
+        let e = match self.audit(
+            || format!("execve({cmd:?}, {args:?}, {env:?})"),
+            || execve(&cmd, &args, &env),
+        ) {
+            Ok(never) => match never {},
+            Err(e) => e,
+        };
+        if e == Errno::E2BIG {
+            let size: usize = args
+                .iter()
+                .chain(&env)
+                .map(|s| s.as_bytes_with_nul().len())
+                .sum();
+            error!(
+                "Argument list too long: {} arguments, {size} bytes",
+                args.len()
+            );
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "argument list too long for execve ({} arguments, {size} bytes); \
+                     reduce the number of arguments or pass them through a response file",
+                    args.len()
+                ),
+            )));
+        }
+
+        Err(Box::new(e))
+    }
+
+    /// Run an operation, logging it together with its result in audit mode.
+    /// `call` describes the operation, and is only evaluated when auditing.
+    fn audit<T, E: Display>(
+        &self,
+        call: impl FnOnce() -> String,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        audit(self.audit, call, f)
+    }
+
+    /// TERM for the entrypoint: the host's, or the fallback if running in a terminal
+    fn term(&self) -> Option<String> {
+        if let Ok(term) = env::var("TERM") {
+            return Some(term);
+        }
+        // GUI apps and services have no use for TERM
+        if isatty(nix::libc::STDOUT_FILENO).unwrap_or(false) {
+            Some(self.term.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Check that none of the container's mounts propagate to the host.
+    /// Shared mounts are only warned about, unless verify_propagation is set.
+    fn check_propagation(&self) -> Result<(), std::io::Error> {
+        let mounts = mountinfo::read()?;
+        let mount_dir = fs::canonicalize(&self.mount_dir)?;
+        let shared: Vec<_> = mountinfo::below(&mounts, &mount_dir)
+            .filter(|m| m.is_shared())
+            .map(|m| &m.mount_point)
+            .collect();
+        if shared.is_empty() {
+            debug!("No mounts below {mount_dir:?} are shared");
+            return Ok(());
+        }
+
+        if self.verify_propagation {
+            error!("Mounts in the container would propagate to the host: {shared:?}");
+            return Err(std::io::Error::other(format!(
+                "{} mounts in the container are shared with the host",
+                shared.len()
+            )));
+        }
+        warn!("Mounts in the container may propagate to the host: {shared:?}");
+        Ok(())
+    }
+
+    /// Write launch metrics in the Prometheus text format to the opened metrics file, if any,
+    /// together with the exit code of the entrypoint once it has exited
+    fn write_metrics(
+        &self,
+        file: Option<&File>,
+        launch_time: Duration,
+        mount_time: Duration,
+        exit_code: Option<i32>,
+    ) {
+        let (Some(mut file), Some(path)) = (file, &self.metrics_file) else {
+            return;
+        };
+        let mut metrics = vec![
+            (
+                "apprun_launch_seconds",
+                "Time from starting AppRun until the container was ready",
+                launch_time.as_secs_f64(),
+            ),
+            (
+                "apprun_mount_seconds",
+                "Time spent assembling the container's mounts",
+                mount_time.as_secs_f64(),
+            ),
+            (
+                "apprun_binds",
+                "Number of bind mounts created",
+                self.bind_count.get() as f64,
+            ),
+            (
+                "apprun_failed_binds",
+                "Number of bind mounts that failed and were skipped",
+                self.failed_bind_count.get() as f64,
+            ),
+        ];
+        if let Some(code) = exit_code {
+            metrics.push((
+                "apprun_exit_code",
+                "Exit code of the entrypoint",
+                f64::from(code),
+            ));
+        }
+
+        let mut contents = String::new();
+        for (name, help, value) in metrics {
+            contents += &format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+        }
+        info!("Writing metrics to {path:?}");
+        if let Err(e) = file.write_all(contents.as_bytes()) {
+            warn!("Failed to write metrics to {path:?}: {e}");
+        }
+    }
+
+    /// The resolved configuration as a JSON object, for --print-config.
+    /// `binds` is null when the host's root directories are bound, since listing them
+    /// would mean probing the host.
+    fn config_json(&self) -> String {
+        let path = |path: &Path| json_log::quote(&path.to_string_lossy());
+        let list = |items: Vec<String>| format!("[{}]", items.join(","));
+
+        let explicit = self.binds.is_some() || !self.bind_tries.is_empty();
+        let binds = if explicit {
+            let required = self.binds.iter().flatten().map(|bind| (bind, true));
+            let tries = self.bind_tries.iter().map(|bind| (bind, false));
+            list(
+                required
+                    .chain(tries)
+                    .map(|(bind, required)| {
+                        format!(
+                            r#"{{"source":{},"dest":{},"required":{required}}}"#,
+                            path(&bind.source),
+                            path(&bind.dest)
+                        )
+                    })
+                    .collect(),
+            )
+        } else {
+            "null".to_string()
+        };
+        let mut bind_timeouts: Vec<_> = self.bind_timeouts.iter().collect();
+        bind_timeouts.sort_by(|a, b| a.0.cmp(b.0));
+        let bind_timeouts = bind_timeouts
+            .into_iter()
+            .map(|(dir, seconds)| format!("{}:{seconds}", path(dir)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut clone_flags = self.clone_flags();
+        if self.unshare_pid {
+            clone_flags |= CloneFlags::CLONE_NEWPID;
+        }
+        let namespaces = NS_LINKS
+            .iter()
+            .filter(|(flag, _, _)| clone_flags.contains(*flag))
+            .map(|(_, name, _)| json_log::quote(name))
+            .collect();
+
+        format!(
+            r#"{{"nix_dir":{},"mount_dir":{},"entrypoint":{},"exec_search":{},"args":{},"binds":{binds},"ro_binds":{},"excludes":{},"mount_timeout":{},"bind_timeouts":{{{bind_timeouts}}},"namespaces":{}}}"#,
+            path(&self.nix_dir),
+            path(&self.mount_dir),
+            path(&self.entrypoint),
+            self.exec_search,
+            list(self.args.iter().map(|arg| json_log::quote(arg)).collect()),
+            list(self.ro_binds.iter().map(|dir| path(dir)).collect()),
+            list(
+                self.excludes
+                    .iter()
+                    .map(|name| json_log::quote(&name.to_string_lossy()))
+                    .collect()
+            ),
+            self.mount_timeout,
+            list(namespaces),
+        )
+    }
+
+    /// Copy the resolved entrypoint into the tmpfs, so that it can be run without binding
+    /// the directory containing it. Returns the path of the copy inside the container.
+    /// Store paths it refers to, e.g. the interpreter of a script, are left as they are,
+    /// since they are visible inside the container.
+    fn copy_entrypoint(&self) -> Result<PathBuf, std::io::Error> {
+        let source = store::resolve(self.nix_source(), &self.entrypoint, self.max_symlink_depth)?;
+        let dir = self.mount_dir.join(".apprun");
+        fs::create_dir_all(&dir)?;
+        info!("Copying entrypoint {source:?} into the container");
+        fs::copy(&source, dir.join("entrypoint"))?;
+        Ok(PathBuf::from("/.apprun/entrypoint"))
+    }
+
+    /// Add a note to the audit log, e.g. to explain the operations that follow
+    fn audit_note(&self, note: impl FnOnce() -> String) {
+        if self.audit {
+            audit_log(&format!("# {}", note()));
+        }
+    }
+
+    /// mount(2), audited, or only logged in a dry run
+    fn mount(
+        &self,
+        source: Option<&Path>,
+        target: &Path,
+        fstype: Option<&str>,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> nix::Result<()> {
+        if self.dry_run {
+            info!("Would mount({source:?}, {target:?}, {fstype:?}, {flags:?}, {data:?})");
+            return Ok(());
+        }
+        self.audit(
+            || format!("mount({source:?}, {target:?}, {fstype:?}, {flags:?}, {data:?})"),
+            || retry_on_eintr(|| mount(source, target, fstype, flags, data)),
+        )
+    }
+
+    /// Create a mount point and its parents, unless this is a dry run
+    fn create_dir_all(&self, path: &Path) -> Result<(), std::io::Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        fs::create_dir_all(path)
+    }
+
+    /// Write a file under /proc/self, audited
+    fn write_proc(&self, path: &str, contents: &str) -> Result<(), std::io::Error> {
+        self.audit(
+            || format!("write({path:?}, {contents:?})"),
+            || fs::write(path, contents),
+        )
+    }
+
+    /// The ids the current user and group have inside the container
+    fn inside_ids(&self, uid: Uid, gid: Gid) -> (Uid, Gid) {
+        if self.map_root {
+            (Uid::from_raw(0), Gid::from_raw(0))
+        } else {
+            (uid, gid)
+        }
+    }
+
+    /// The uid and gid maps of the new user namespace: the current ids, as themselves or as root,
+    /// and, if subids is set, the subordinate ids allocated to the current user
+    fn id_maps(&self, uid: Uid, gid: Gid, subids: bool) -> IdMaps {
+        let (inside_uid, inside_gid) = self.inside_ids(uid, gid);
+        let name = User::from_uid(uid).ok().flatten().map(|user| user.name);
+        let ranges = |path: &str| {
+            if !subids {
+                return vec![];
+            }
+            read_subid_ranges(Path::new(path), name.as_deref(), uid.as_raw()).unwrap_or_else(|e| {
+                warn!("Failed to read {path}: {e}");
+                vec![]
+            })
+        };
+        IdMaps {
+            uid: UidMap::with_ranges(inside_uid, uid, &ranges("/etc/subuid"))
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            gid: GidMap::with_ranges(inside_gid, gid, &ranges("/etc/subgid"))
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
+
+    /// Fork newuidmap and newgidmap to write maps with subordinate ids, or a gid map with
+    /// setgroups allowed, which only root can write itself. Without the helpers, the maps are
+    /// cut down to the current ids.
+    fn spawn_id_map_helpers(
+        &self,
+        maps: &mut IdMaps,
+    ) -> Result<Option<IdMapHelpers>, std::io::Error> {
+        let single = maps.uid.len() == 1 && maps.gid.len() == 1;
+        if Uid::effective().is_root() || (single && !self.allow_setgroups) {
+            return Ok(None);
+        }
+        let (Some(newuidmap), Some(newgidmap)) =
+            (find_helper("newuidmap"), find_helper("newgidmap"))
+        else {
+            if self.allow_setgroups {
+                error!(
+                    "--allow-setgroups needs newuidmap and newgidmap, which were not found in PATH"
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "newuidmap or newgidmap not found",
+                ));
+            }
+            warn!("newuidmap or newgidmap not found, mapping only the current uid and gid");
+            maps.uid.truncate(1);
+            maps.gid.truncate(1);
+            return Ok(None);
+        };
+
+        let pid = getpid();
+        let gate = id_map::gate()?;
+        let uid = spawn_helper(&newuidmap, pid, &maps.uid, gate)?;
+        let gid = spawn_helper(&newgidmap, pid, &maps.gid, gate)?;
+        close(gate.0)?;
+        Ok(Some(IdMapHelpers {
+            gate: gate.1,
+            uid,
+            gid,
+        }))
+    }
+
+    /// Write uid_map and gid_map, or let the helpers write them
+    fn write_id_maps(
+        &self,
+        maps: &IdMaps,
+        helpers: Option<IdMapHelpers>,
+    ) -> Result<(), std::io::Error> {
+        let uid_map = maps.uid.join("\n");
+        let gid_map = maps.gid.join("\n");
+
+        if let Some(helpers) = helpers {
+            if !self.allow_setgroups {
+                self.write_proc("/proc/self/setgroups", "deny")?;
+            }
+            // The helpers start once the last write end of the gate is closed
+            close(helpers.gate)?;
+            self.audit(
+                || format!("newuidmap({uid_map:?})"),
+                || wait_helper(helpers.uid),
+            )?;
+            self.audit(
+                || format!("newgidmap({gid_map:?})"),
+                || wait_helper(helpers.gid),
+            )?;
+            info!("Wrote uid_map and gid_map with newuidmap and newgidmap");
+            // newgidmap denies setgroups itself when the user has no subordinate gids
+            if self.allow_setgroups && fs::read_to_string("/proc/self/setgroups")?.trim() != "allow"
+            {
+                error!(
+                    "newgidmap denied setgroups, which it does unless the user has \
+                     subordinate gids in /etc/subgid"
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "setgroups could not be allowed",
+                ));
+            }
+            return Ok(());
+        }
+
+        self.write_proc("/proc/self/uid_map", &uid_map)?;
+        info!("Wrote uid_map");
+        self.write_proc("/proc/self/setgroups", "deny")?;
+        self.write_proc("/proc/self/gid_map", &gid_map)?;
+        info!("Wrote gid_map");
+
+        Ok(())
+    }
+
+    /// Check if a path exists, giving up after the mount timeout, or the one given for
+    /// the path or a parent with --bind-timeout
+    fn probe_exists(&self, path: &Path) -> bool {
+        let path_name = path.file_name().unwrap_or(path.as_os_str());
+        let level = self.skip_log_level();
+        let seconds = path
+            .ancestors()
+            .find_map(|ancestor| self.bind_timeouts.get(ancestor))
+            .copied()
+            .unwrap_or(self.mount_timeout);
+        let timeout = Duration::from_secs_f32(seconds);
+        match self.prober.exists(path, timeout) {
+            Err(e) => {
+                log!(level, "Error: {}", e);
+                log!(level, "Timed out to check existance of {path_name:?}. Maybe it's a broken symlink or broken NFS mount?");
+                false
+            }
+            Ok(Err(e)) => {
+                log!(level, "Error: {}", e);
+                log!(level, "Failed to check existance of {path_name:?}.");
+                false
+            }
+            Ok(Ok(exists)) => exists,
+        }
+    }
+
+    /// Log level for messages about skipped binds
+    fn skip_log_level(&self) -> Level {
+        if self.quiet_skips {
+            Level::Debug
+        } else {
+            Level::Warn
+        }
+    }
+
+    /// Perform a recursive bind mount, returning whether it succeeded.
+    /// If the mount is required, failing to mount is an error rather than a warning.
+    fn rec_bind_mount(
+        &self,
+        path: &Path,
+        mount_path: &Path,
+        required: bool,
+    ) -> Result<bool, std::io::Error> {
+        let path_name = path.file_name().unwrap_or(path.as_os_str());
+        self.check_mount_limit(1, path)?;
+
+        debug!("Creating bind mount for {path_name:?}");
+        if !self.dry_run {
+            create_mount_point(path, mount_path)?;
+        }
+        let mount_result = self.mount(Some(path), mount_path, None, rec_bind_flags(), None);
+        self.record_bind(path, mount_result, required)
+    }
+
+    /// Perform the recursive bind mounts of binds, pairs of a path and its mount point,
+    /// from a few threads at once, since each mount syscall takes a while
+    fn rec_bind_mount_all(&self, binds: &[(PathBuf, PathBuf)]) -> Result<(), std::io::Error> {
+        const MAX_WORKERS: usize = 8;
+
+        let workers = thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_WORKERS)
+            .min(binds.len());
+        if self.dry_run || workers <= 1 {
+            for (path, mount_path) in binds {
+                self.rec_bind_mount(path, mount_path, false)?;
+            }
+            return Ok(());
+        }
+        if let Some((path, _)) = binds.first() {
+            self.check_mount_limit(binds.len(), path)?;
+        }
+
+        let start = Instant::now();
+        let audit_enabled = self.audit;
+        let next = AtomicUsize::new(0);
+        let create_lock = Mutex::new(());
+        let results: Vec<(Vec<_>, Duration)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = vec![];
+                        // Time spent binding, which is what binding one at a time would take
+                        let mut busy = Duration::ZERO;
+                        while let Some((path, mount_path)) =
+                            binds.get(next.fetch_add(1, Ordering::Relaxed))
+                        {
+                            let started = Instant::now();
+                            results.push((
+                                path,
+                                bind_in_worker(path, mount_path, &create_lock, audit_enabled),
+                            ));
+                            busy += started.elapsed();
+                        }
+                        (results, busy)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let elapsed = start.elapsed();
+        let busy: Duration = results.iter().map(|(_, busy)| *busy).sum();
+        debug!(
+            "Bound {} paths with {workers} threads in {elapsed:?}, {:.1}x as fast as one at a time ({busy:?})",
+            binds.len(),
+            busy.as_secs_f64() / elapsed.as_secs_f64()
+        );
+
+        let results = results.into_iter().flat_map(|(results, _)| results);
+
+        for (path, result) in results {
+            self.record_bind(path, result?, false)?;
+        }
+        Ok(())
+    }
+
+    /// Fail if creating count more bind mounts, starting with path, would exceed the limit.
+    /// Checking up front is clearer than running into ENOSPC from the kernel's mount limit.
+    fn check_mount_limit(&self, count: usize, path: &Path) -> Result<(), std::io::Error> {
+        if let Some(limit) = self.limit_mounts {
+            if self.bind_count.get() + count > limit {
+                error!("Reached the limit of {limit} bind mounts before mounting {path:?}");
+                return Err(std::io::Error::other(format!(
+                    "more than {limit} bind mounts needed ({} mounted so far); \
+                     narrow the set of binds with --apprun-bind",
+                    self.bind_count.get()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a bind mount of path, and return whether it succeeded.
+    /// A failure is returned as an error if the bind is required, and only warned about otherwise.
+    fn record_bind(
+        &self,
+        path: &Path,
+        result: nix::Result<()>,
+        required: bool,
+    ) -> Result<bool, std::io::Error> {
+        match result {
+            Ok(()) => {
+                self.bind_count.set(self.bind_count.get() + 1);
+                Ok(true)
+            }
+            Err(e) => {
+                self.failed_bind_count.set(self.failed_bind_count.get() + 1);
+                if required {
+                    error!("Failed to mount {path:?}: {e}");
+                    return Err(e.into());
+                }
+                let path_name = path.file_name().unwrap_or(path.as_os_str());
+                warn!("Failed to mount {path_name:?}: {e:?}");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Whether dest is where the host's /sys or /proc is bound, which are made read-only,
+    /// since writing to them would change the host's kernel settings.
+    /// A fresh /proc for a new PID namespace is mounted over the bound one anyway.
+    fn is_readonly_kernel_fs(&self, dest: &Path) -> bool {
+        !self.rw_sys
+            && (dest == Path::new("/sys") || (dest == Path::new("/proc") && !self.unshare_pid))
+    }
+
+    /// Flags for the bind of the host path dest given with --nosuid and --nodev.
+    /// The bundled /nix is trusted, and /dev is only there for its devices.
+    fn hardening_flags(&self, dest: &Path) -> MsFlags {
+        let mut flags = MsFlags::empty();
+        if dest.starts_with("/nix") {
+            return flags;
+        }
+        if self.nosuid {
+            flags |= MsFlags::MS_NOSUID;
+        }
+        if self.nodev && !dest.starts_with("/dev") {
+            flags |= MsFlags::MS_NODEV;
+        }
+        flags
+    }
+
+    /// Remount a bind mount read-only
+    fn remount_readonly(&self, mount_path: &Path) -> Result<(), std::io::Error> {
+        self.remount(mount_path, MsFlags::MS_RDONLY)
+    }
+
+    /// Remount a bind mount with flags, like MS_RDONLY or MS_NOSUID, added to its own
+    fn remount(&self, mount_path: &Path, flags: MsFlags) -> Result<(), std::io::Error> {
+        // In a user namespace, flags like nosuid inherited from the host are locked,
+        // and the remount fails unless we keep them.
+        // The ST_* flags from statvfs have the same values as the MS_* mount flags on Linux.
+        let locked = MsFlags::MS_RDONLY
+            | MsFlags::MS_NOSUID
+            | MsFlags::MS_NODEV
+            | MsFlags::MS_NOEXEC
+            | MsFlags::MS_NOATIME
+            | MsFlags::MS_NODIRATIME
+            | MsFlags::MS_RELATIME;
+        let current = if self.dry_run {
+            MsFlags::empty()
+        } else {
+            MsFlags::from_bits_truncate(statvfs(mount_path)?.flags().bits()) & locked
+        };
+
+        debug!("Remounting {mount_path:?} with {flags:?}");
+        self.mount(
+            None,
+            mount_path,
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | flags | current,
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Replace the host's /dev/shm and /dev/mqueue, where POSIX shared memory and message
+    /// queues show up, with ones for the new IPC namespace
+    fn mount_ipc(&self) -> Result<(), std::io::Error> {
+        let dev = self.mount_dir.join("dev");
+        // A minimal /dev has a fresh /dev/shm already
+        if !self.minimal_dev && dev.join("shm").is_dir() {
+            info!(
+                "Mounting tmpfs for the IPC namespace to {:?}",
+                dev.join("shm")
+            );
+            self.mount(
+                Some(Path::new("tmpfs")),
+                &dev.join("shm"),
+                Some("tmpfs"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some("mode=1777"),
+            )?;
+        }
+        if dev.join("mqueue").is_dir() {
+            info!(
+                "Mounting mqueue for the IPC namespace to {:?}",
+                dev.join("mqueue")
+            );
+            self.mount(
+                Some(Path::new("mqueue")),
+                &dev.join("mqueue"),
+                Some("mqueue"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Mount the cgroup2 hierarchy over the host's /sys/fs/cgroup, so that it is seen from
+    /// the root of the new cgroup namespace
+    fn mount_cgroup(&self) -> Result<(), std::io::Error> {
+        let mount_path = self.mount_dir.join("sys/fs/cgroup");
+        if !mount_path.is_dir() {
+            debug!("{mount_path:?} does not exist, not mounting cgroup2");
+            return Ok(());
+        }
+        // Replacing a cgroup v1 or hybrid tree with cgroup2 would hide its controllers
+        let unified = statfs("/sys/fs/cgroup")
+            .map(|fs| fs.filesystem_type() == CGROUP2_SUPER_MAGIC)
+            .unwrap_or(false);
+        if !unified {
+            warn!("The host doesn't use cgroup2 only, keeping its /sys/fs/cgroup");
+            return Ok(());
+        }
+        info!("Mounting cgroup2 to {mount_path:?}");
+        let result = self.mount(
+            Some(Path::new("cgroup2")),
+            &mount_path,
+            Some("cgroup2"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None,
+        );
+        if let Err(e) = result {
+            warn!("Failed to mount cgroup2, keeping the host's /sys/fs/cgroup: {e}");
+        }
+        Ok(())
+    }
+
+    /// Mount a tmpfs at /dev with only the host's device nodes most apps need bound into it,
+    /// a fresh /dev/shm, and the usual links to /proc/self/fd
+    fn mount_minimal_dev(&self) -> Result<(), std::io::Error> {
+        // pts is bound from the host, so that the terminal AppRun runs in keeps its name
+        const NODES: [&str; 9] = [
+            "null", "zero", "full", "random", "urandom", "tty", "ptmx", "pts", "dri",
+        ];
+        const LINKS: [(&str, &str); 4] = [
+            ("fd", "/proc/self/fd"),
+            ("stdin", "/proc/self/fd/0"),
+            ("stdout", "/proc/self/fd/1"),
+            ("stderr", "/proc/self/fd/2"),
+        ];
+
+        let dev = self.mount_dir.join("dev");
+        self.create_dir_all(&dev)?;
+        info!("Mounting tmpfs for minimal /dev to {dev:?}");
+        self.mount(
+            Some(Path::new("tmpfs")),
+            &dev,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("mode=755"),
+        )?;
+
+        for name in NODES {
+            let host_path = Path::new("/dev").join(name);
+            if !host_path.exists() {
+                debug!("{host_path:?} does not exist on the host, leaving it out");
+                continue;
+            }
+            self.rec_bind_mount(&host_path, &dev.join(name), false)?;
+        }
+
+        let shm = dev.join("shm");
+        self.create_dir_all(&shm)?;
+        self.mount(
+            Some(Path::new("tmpfs")),
+            &shm,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("mode=1777"),
+        )?;
+
+        if !self.dry_run {
+            for (name, target) in LINKS {
+                std::os::unix::fs::symlink(target, dev.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mount a tmpfs for /tmp, which is gone once the container is
+    fn mount_private_tmp(&self) -> Result<(), std::io::Error> {
+        let mount_path = self.mount_dir.join("tmp");
+        // A /tmp recreated from a symlink on the host would lead the mount out of the container
+        let is_symlink = fs::symlink_metadata(&mount_path).is_ok_and(|m| m.is_symlink());
+        if is_symlink && !self.dry_run {
+            fs::remove_file(&mount_path)?;
+        }
+        self.create_dir_all(&mount_path)?;
+
+        let mut data = "mode=1777".to_string();
+        if let Some(size) = &self.tmpfs_size {
+            data += &format!(",size={size}");
+        }
+        info!("Mounting private tmpfs to {mount_path:?}");
+        self.mount(
+            Some(Path::new("tmpfs")),
+            &mount_path,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(&data),
+        )?;
+        Ok(())
+    }
+
+    /// Mount a tmpfs owned by the user with mode 0700 as XDG_RUNTIME_DIR
+    fn mount_xdg_runtime(&self, host_uid: Uid) -> Result<(), std::io::Error> {
+        // The owner is given as the ids inside the namespace, which differ with --map-root
+        let (uid, gid) = (Uid::effective(), Gid::effective());
+        let dir = xdg_runtime_dir(host_uid);
+        let mount_path = self.mount_dir.join(dir.strip_prefix("/").unwrap());
+        self.create_dir_all(&mount_path)?;
+        info!("Mounting tmpfs for XDG_RUNTIME_DIR to {mount_path:?}");
+        self.mount(
+            Some(Path::new("tmpfs")),
+            &mount_path,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(&format!("mode=700,uid={uid},gid={gid}")),
+        )?;
+        Ok(())
+    }
+
+    /// Set up /proc inside the container as requested
+    fn setup_proc(&self) -> Result<(), std::io::Error> {
+        if self.unshare_pid || self.proc_hidepid {
+            self.mount_proc()?;
+        }
+        if self.proc_mask {
+            self.mask_proc()?;
+        }
+        Ok(())
+    }
+
+    /// Mount a fresh /proc, with hidepid=2 if requested so the app can't see other users' processes
+    fn mount_proc(&self) -> Result<(), std::io::Error> {
+        let mount_path = self.mount_dir.join("proc");
+        self.create_dir_all(&mount_path)?;
+        let data = self.proc_hidepid.then_some("hidepid=2");
+        info!("Mounting proc with {data:?} to {mount_path:?}");
+        let result = self.mount(
+            Some(Path::new("proc")),
+            &mount_path,
+            Some("proc"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            data,
+        );
+        match result {
+            Ok(()) => Ok(()),
+            Err(Errno::EPERM) if self.new_user_namespace && !self.unshare_pid => {
+                error!("Cannot mount proc: a user namespace can only mount proc for its own PID namespace");
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "mounting a fresh /proc without root needs --unshare-pid",
+                ))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Hide the sensitive parts of /proc inside the container, like container runtimes do
+    fn mask_proc(&self) -> Result<(), std::io::Error> {
+        let proc = self.mount_dir.join("proc");
+        if !proc.is_dir() {
+            warn!("/proc is not mounted in the container, nothing to mask");
+            return Ok(());
+        }
+
+        // Files are masked by binding /dev/null over them
+        for name in ["kcore", "keys", "sysrq-trigger", "timer_list"] {
+            let path = proc.join(name);
+            if !path.exists() {
+                continue;
+            }
+            debug!("Masking {path:?}");
+            self.mount(
+                Some(Path::new("/dev/null")),
+                &path,
+                None,
+                MsFlags::MS_BIND,
+                None,
+            )?;
+        }
+
+        // Directories are bound over themselves read-only
+        let path = proc.join("sys");
+        if path.is_dir() {
+            debug!("Making {path:?} read-only");
+            self.mount(
+                Some(&path),
+                &path,
+                None,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None,
+            )?;
+            self.remount_readonly(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Bind a host path read-only at the same location inside the container, if it exists
+    fn bind_host_readonly(&self, path: &Path) -> Result<(), std::io::Error> {
+        if !path.exists() {
+            warn!("{path:?} does not exist on the host, not binding it");
+            return Ok(());
+        }
+
+        let Some(mount_path) = mount_path_of(&self.mount_dir, path) else {
+            error!("Cannot bind {path:?} read-only, it must be a path below /");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("read-only bind {path:?} is not below /"),
+            ));
+        };
+        if let Some(parent) = mount_path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        info!("Creating read-only bind mount for {path:?}");
+        if self.rec_bind_mount(path, &mount_path, false)? {
+            self.remount(&mount_path, MsFlags::MS_RDONLY | self.hardening_flags(path))?;
+        }
+        Ok(())
+    }
+
+    /// Host paths GUI apps need to reach the display server, the sound server and D-Bus
+    fn desktop_paths(&self) -> Vec<PathBuf> {
+        let runtime_dir = env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute());
+        if runtime_dir.is_none() {
+            warn!("XDG_RUNTIME_DIR is not set, Wayland and PipeWire apps may not work");
+        }
+        runtime_dir
+            .into_iter()
+            .chain(["/run/dbus", "/tmp/.X11-unix"].map(PathBuf::from))
+            .collect()
+    }
+
+    /// Bind a host path needed by --desktop at the same location, if it exists
+    fn bind_desktop_path(&self, path: &Path) -> Result<(), std::io::Error> {
+        if !self.probe_exists(path) {
+            debug!("Skipping non-existent desktop path {path:?}");
+            return Ok(());
+        }
+        let Some(mount_path) = mount_path_of(&self.mount_dir, path) else {
+            warn!("Skipping {path:?}, which isn't a path below /");
+            return Ok(());
+        };
+        if let Some(parent) = mount_path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        info!("Creating bind mount for desktop path {path:?}");
+        let flags = self.hardening_flags(path);
+        if self.rec_bind_mount(path, &mount_path, false)? && !flags.is_empty() {
+            self.remount(&mount_path, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Copy a host file into the container, so that the app sees a snapshot from startup,
+    /// and changing it leaves the host's file alone
+    fn copy_host_file(&self, path: &Path) -> Result<(), std::io::Error> {
+        if !path.is_file() {
+            warn!("{path:?} is not a file on the host, not copying it");
+            return Ok(());
+        }
+        if mount_path_of(&self.mount_dir, path).is_none() {
+            error!("Cannot copy {path:?}, it must be a path below /");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("copied file {path:?} is not below /"),
+            ));
+        }
+        info!("Copying {path:?} into the container");
+        if self.dry_run {
+            return Ok(());
+        }
+        self.write_container_file(path, &fs::read(path)?, fs::metadata(path)?.permissions())
+    }
+
+    /// Write a file at path, which must be below /, inside the container without touching
+    /// the host: into the tmpfs, or bound over the file if its directory is bound from the host
+    fn write_container_file(
+        &self,
+        path: &Path,
+        contents: &[u8],
+        permissions: fs::Permissions,
+    ) -> Result<(), std::io::Error> {
+        let mount_path = mount_path_of(&self.mount_dir, path).unwrap();
+        let parent = mount_path.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        if fs::metadata(parent)?.dev() == fs::metadata(&self.mount_dir)?.dev() {
+            fs::write(&mount_path, contents)?;
+            return fs::set_permissions(&mount_path, permissions);
+        }
+
+        // Writing into a directory bound from the host would change the host's file,
+        // so the file is kept in the tmpfs and bound over the host's one instead
+        if mount_path.is_symlink() {
+            warn!(
+                "{path:?} is a symlink inside the container, not replacing it; its target is {:?}",
+                fs::read_link(&mount_path)?
+            );
+            return Ok(());
+        }
+        if !mount_path.exists() {
+            warn!("{path:?} is missing from the directory bound over it, not replacing it");
+            return Ok(());
+        }
+        // The container's root is unbindable, so the files need a tmpfs of their own
+        let files = self.mount_dir.join(".apprun/files");
+        if !files.exists() {
+            fs::create_dir_all(&files)?;
+            self.mount(
+                Some(Path::new("tmpfs")),
+                &files,
+                Some("tmpfs"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some("mode=755"),
+            )?;
+        }
+        let file = files.join(path.strip_prefix("/").unwrap());
+        fs::create_dir_all(file.parent().unwrap())?;
+        fs::write(&file, contents)?;
+        fs::set_permissions(&file, permissions)?;
+        self.mount(Some(&file), &mount_path, None, MsFlags::MS_BIND, None)?;
+        Ok(())
+    }
+
+    /// Write an /etc/passwd and /etc/group describing the user and group uid and gid on the
+    /// host by the ids they have inside the container, so that tools like whoami work in a
+    /// user namespace
+    fn write_fake_passwd(&self, uid: Uid, gid: Gid) -> Result<(), std::io::Error> {
+        let (inside_uid, inside_gid) = self.inside_ids(uid, gid);
+        let user = User::from_uid(uid).ok().flatten();
+        let group_name = Group::from_gid(gid).ok().flatten().map(|group| group.name);
+        let name = match &user {
+            _ if inside_uid.is_root() => "root".to_string(),
+            Some(user) => user.name.clone(),
+            None => env::var("USER").unwrap_or_else(|_| "user".to_string()),
+        };
+        let home = user.as_ref().map_or_else(
+            || env::var_os("HOME").map_or_else(|| PathBuf::from("/"), PathBuf::from),
+            |user| user.dir.clone(),
+        );
+        let shell = user
+            .as_ref()
+            .map_or_else(|| PathBuf::from("/bin/sh"), |user| user.shell.clone());
+
+        let mut passwd = String::new();
+        let mut group = String::new();
+        if !inside_uid.is_root() {
+            passwd += "root:x:0:0:root:/root:/bin/sh\n";
+        }
+        if inside_gid.as_raw() != 0 {
+            group += "root:x:0:\n";
+        }
+        passwd += &format!(
+            "{name}:x:{inside_uid}:{inside_gid}:{name}:{}:{}\n",
+            home.display(),
+            shell.display()
+        );
+        let group_name = match group_name {
+            _ if inside_gid.as_raw() == 0 => "root".to_string(),
+            Some(group_name) => group_name,
+            None => name.clone(),
+        };
+        group += &format!("{group_name}:x:{inside_gid}:\n");
+        // Files of unmapped ids show up as owned by the overflow id
+        if inside_uid.as_raw() != 65534 {
+            passwd += "nobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin\n";
+        }
+        if inside_gid.as_raw() != 65534 {
+            group += "nogroup:x:65534:\n";
+        }
+
+        info!("Writing /etc/passwd and /etc/group for {name} ({inside_uid}:{inside_gid})");
+        if self.dry_run {
+            return Ok(());
+        }
+        let permissions = fs::Permissions::from_mode(0o644);
+        self.write_container_file(
+            Path::new("/etc/passwd"),
+            passwd.as_bytes(),
+            permissions.clone(),
+        )?;
+        self.write_container_file(Path::new("/etc/group"), group.as_bytes(), permissions)
+    }
+
+    /// Mount all nonexist entries of /nix/store from host
+    fn mount_nix(&self, host_nix: &Path, mount_nix: &Path) -> Result<(), std::io::Error> {
+        let host_store = host_nix.join("store");
+        let mount_store = mount_nix.join("store");
+        if !host_store.exists() {
+            return Ok(());
+        }
+        if !mount_store.exists() {
+            self.create_dir_all(&mount_store)?;
+        }
+
+        info!("Mounting {host_store:?}/* to {mount_store:?}");
+        let mut binds = vec![];
+        for entry in host_store.read_dir()? {
+            let entry = entry?;
+            let path = entry.path();
+
+            // Check if this path exists in the container
+            let mount_path = mount_store.join(entry.file_name());
+            if mount_path.exists() {
+                continue;
+            }
+            binds.push((path, mount_path));
+        }
+
+        self.rec_bind_mount_all(&binds)
+    }
+
+    /// Mount a squashfs image of /nix through a loop device
+    fn mount_nix_image(&self, image: &Path, mount_path: &Path) -> Result<(), std::io::Error> {
+        if self.dry_run {
+            info!("Would mount squashfs image {image:?} to {mount_path:?} through a loop device");
+            return Ok(());
+        }
+        let device = self
+            .audit(
+                || format!("attach_loop({image:?})"),
+                || loop_dev::attach(image),
+            )
+            .inspect_err(|e| {
+                if matches!(e, Errno::EACCES | Errno::EPERM | Errno::ENOENT) {
+                    error!("Cannot set up a loop device for {image:?}: {e}. Mounting a squashfs image needs root, extract it and pass the directory instead");
+                } else {
+                    error!("Cannot set up a loop device for {image:?}: {e}");
+                }
+            })?;
+
+        info!(
+            "Mounting squashfs image {image:?} from {:?} to {mount_path:?}",
+            device.path
+        );
+        self.audit_note(|| format!("{}: {image:?} -> {mount_path:?}", self.store_label));
+        self.mount(
+            Some(&device.path),
+            mount_path,
+            Some("squashfs"),
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Stack the nix layers over base as a read-only overlay at mount_path.
+    /// Images are mounted on a staging directory first, which is detached and removed again
+    /// once the overlay holds on to them, so that they don't show up in the container.
+    fn mount_nix_layers(&self, base: &Path, mount_path: &Path) -> Result<(), std::io::Error> {
+        let staging = self.mount_dir.join(".apprun-layers");
+        let layers = std::iter::once(base).chain(self.nix_layers.iter().map(PathBuf::as_path));
+        let mut lowerdirs = vec![];
+        let mut staged = vec![];
+        for (i, layer) in layers.enumerate() {
+            if layer.is_file() {
+                let dir = staging.join(i.to_string());
+                self.create_dir_all(&dir)?;
+                self.mount_nix_image(layer, &dir)?;
+                staged.push(dir.clone());
+                lowerdirs.push(dir);
+            } else {
+                lowerdirs.push(layer.to_path_buf());
+            }
+        }
+
+        // overlayfs takes the uppermost layer first
+        let lowerdirs: Vec<_> = lowerdirs
+            .iter()
+            .rev()
+            .map(|dir| overlay_escape(dir))
+            .collect();
+        info!(
+            "Mounting {} nix layers as an overlay to {mount_path:?}",
+            lowerdirs.len()
+        );
+        self.mount(
+            Some(Path::new("overlay")),
+            mount_path,
+            Some("overlay"),
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(&format!("lowerdir={}", lowerdirs.join(":"))),
+        )?;
+
+        if self.dry_run {
+            return Ok(());
+        }
+        for dir in &staged {
+            self.audit(
+                || format!("umount2({dir:?}, MNT_DETACH)"),
+                || retry_on_eintr(|| umount2(dir, MntFlags::MNT_DETACH)),
+            )?;
+        }
+        if !staged.is_empty() {
+            fs::remove_dir_all(&staging)?;
+        }
+        Ok(())
+    }
+
+    /// Build /nix on a tmpfs from the store paths of the host, then add the bundled
+    /// store paths the host doesn't have
+    fn mount_merged_nix(&self, nix_source: &Path, mount_path: &Path) -> Result<(), std::io::Error> {
+        info!("Mounting tmpfs for merged /nix to {mount_path:?}");
+        self.mount(
+            Some(Path::new("tmpfs")),
+            mount_path,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("mode=755"),
+        )?;
+        self.create_dir_all(&mount_path.join("store"))?;
+
+        let host_nix = Path::new("/nix");
+        if nix_source != host_nix {
+            self.mount_nix(host_nix, mount_path)?;
+        }
+        self.audit_note(|| format!("{}: {nix_source:?} -> {mount_path:?}", self.store_label));
+        self.mount_nix(nix_source, mount_path)
+    }
+
+    /// Create the namespaces given by clone_flags, and set up the user, UTS and network
+    /// namespaces among them
+    fn create_namespaces(
+        &self,
+        clone_flags: CloneFlags,
+        uid: Uid,
+        gid: Gid,
+    ) -> Result<(), std::io::Error> {
+        // Inside a user namespace, e.g. in a container, ids are already relative to it,
+        // while /etc/subuid and /etc/subgid usually describe the initial one
+        let nested = match read_uid_map() {
+            Ok(maps) => !is_initial_uid_map(&maps),
+            Err(e) => {
+                warn!("Failed to read uid_map: {e}");
+                false
+            }
+        };
+        if nested && clone_flags.contains(CloneFlags::CLONE_NEWUSER) {
+            info!(
+                "Already inside a user namespace, mapping only the current uid and gid within it"
+            );
+        }
+
+        // The helpers writing id maps have to be forked before the user namespace is created
+        let mut id_maps = self.id_maps(uid, gid, !nested);
+        let id_map_helpers = if clone_flags.contains(CloneFlags::CLONE_NEWUSER) {
+            self.spawn_id_map_helpers(&mut id_maps)?
+        } else {
+            None
+        };
+
+        info!("Creating new mount namespace with {clone_flags:?}");
+        if let Err(e) = self.audit(
+            || format!("unshare({clone_flags:?})"),
+            || retry_on_eintr(|| unshare(clone_flags)),
+        ) {
+            if let Some(helpers) = id_map_helpers {
+                helpers.cancel();
+            }
+            // Going on would mount over the host's own mounts
+            let hint = match e {
+                Errno::EPERM if self.new_user_namespace => {
+                    "Unprivileged user namespaces are probably disabled, \
+                     see sysctl kernel.unprivileged_userns_clone, \
+                     or kernel.apparmor_restrict_unprivileged_userns on Ubuntu"
+                }
+                Errno::EPERM => "Did you forget to run me as root?",
+                Errno::ENOSPC => {
+                    "Too many namespaces exist already, see sysctl user.max_user_namespaces \
+                     and user.max_mnt_namespaces"
+                }
+                _ => "",
+            };
+            error!("Failed to create new mount namespace: {e}. {hint}");
+            return Err(std::io::Error::new(
+                std::io::Error::from(e).kind(),
+                format!("failed to create new mount namespace: {e}"),
+            ));
+        }
+
+        if clone_flags.contains(CloneFlags::CLONE_NEWUSER) {
+            // A new user namespace starts without maps, and they can only be written once
+            match read_uid_map() {
+                Ok(maps) if !maps.is_empty() => {
+                    warn!("uid_map is already set, so no new user namespace was created; not writing id maps");
+                    if let Some(helpers) = id_map_helpers {
+                        helpers.cancel();
+                    }
+                }
+                _ => {
+                    info!("Created new user namespace");
+                    self.write_id_maps(&id_maps, id_map_helpers)?;
+                }
+            }
+        }
+
+        if clone_flags.contains(CloneFlags::CLONE_NEWUTS) {
+            let hostname = match &self.hostname {
+                Some(hostname) => hostname.clone(),
+                None if self.hostname_from_bundle => self.bundle_hostname()?,
+                None => "appimage".to_string(),
+            };
+            info!("Setting hostname to {hostname}");
+            self.audit(
+                || format!("sethostname({hostname:?})"),
+                || retry_on_eintr(|| sethostname(&hostname)),
+            )?;
+        }
+
+        if clone_flags.contains(CloneFlags::CLONE_NEWNET) {
+            // A new network namespace only has lo, and it is down
+            info!("Bringing up the loopback interface");
+            self.audit(
+                || "ioctl(SIOCSIFFLAGS, \"lo\", IFF_UP)".to_string(),
+                || retry_on_eintr(|| net::set_up("lo")),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// The namespaces to create, besides the PID namespace which is created later
+    fn clone_flags(&self) -> CloneFlags {
+        let mut clone_flags = if self.new_user_namespace {
+            CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS
+        } else {
+            CloneFlags::CLONE_NEWNS
+        };
+        if self.unshare_uts || self.hostname.is_some() || self.hostname_from_bundle {
+            clone_flags |= CloneFlags::CLONE_NEWUTS;
+        }
+        if self.unshare_net {
+            clone_flags |= CloneFlags::CLONE_NEWNET;
+        }
+        if self.unshare_ipc {
+            clone_flags |= CloneFlags::CLONE_NEWIPC;
+        }
+        if self.unshare_cgroup {
+            clone_flags |= CloneFlags::CLONE_NEWCGROUP;
+        }
+        clone_flags
+    }
+
+    /// Create a new mount namespace, bind mount everything from / into the mount_dir,
+    /// and bind mount /nix from self.nix_to_mount
+    fn mounts(&self) -> Result<(), std::io::Error> {
+        let (uid, gid) = (Uid::current(), Gid::current());
+        debug!("Current uid: {uid}, gid: {gid}");
+
+        let clone_flags = self.clone_flags();
+        if self.dry_run {
+            info!("Would create namespaces with {clone_flags:?}");
+        } else {
+            self.create_namespaces(clone_flags, uid, gid)?;
+        }
+
+        // Mark all mount points as slave
+        // So that mounts in the container don't propagate to the host
+        // For example, when we unmount /nix in the container, we don't want that to propagate to the host
+        if self.no_slave_root {
+            warn!(
+                "Not remounting / as rslave: unless propagation is already arranged, \
+                 mounts made in the container may propagate to the host"
+            );
+        } else {
+            info!("Mounting / as rslave");
+            self.mount(
+                None,
+                Path::new("/"),
+                None,
+                MsFlags::MS_SLAVE | MsFlags::MS_REC,
+                None,
+            )?;
+        }
+
+        // Mount a tmpfs
+        let mut data = "mode=755".to_string();
+        if let Some(size) = &self.tmpfs_size {
+            data += &format!(",size={size}");
+        }
+        info!("Mounting tmpfs to {:?}", self.mount_dir);
+        self.mount(
+            Some(Path::new("tmpfs")),
+            &self.mount_dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID,
+            Some(&data),
+        )?;
+        // Otherwise the recursive bind of a parent of mount_dir, like /tmp for the default
+        // in $TMPDIR, would copy the container into itself.
+        // This needs its own call, since the propagation type can't be set while mounting.
+        self.mount(None, &self.mount_dir, None, MsFlags::MS_UNBINDABLE, None)?;
+
+        // Each path comes with whether it has to exist
+        let mut paths_to_bind = vec![];
+        let explicit = self.binds.is_some() || !self.bind_tries.is_empty();
+        let readonly_all = !explicit && self.readonly_bind_all;
+        if explicit {
+            let binds = self.binds.iter().flatten();
+            paths_to_bind.extend(binds.map(|bind| (bind.clone(), true)));
+            paths_to_bind.extend(self.bind_tries.iter().map(|bind| (bind.clone(), false)));
+        } else {
+            // Copy over root directories
+            let files = fs::read_dir("/")?;
+            for file in files {
+                let file = file?;
+                let path = file.path();
+                // Excluded paths are never even probed, since they might hang
+                if self.excludes.contains(&file.file_name()) {
+                    info!("Excluding {path:?}");
+                    continue;
+                }
+                // Links like /bin -> usr/bin on merged-usr systems are recreated, not followed
+                if file.file_type()?.is_symlink() && path != Path::new("/nix") {
+                    self.copy_root_symlink(&path)?;
+                    continue;
+                }
+                paths_to_bind.push((Bind::same_path(path), false));
+            }
+        }
+        for bind in &self.label_binds {
+            match disk::find_mount(&self.disk_dir, bind.tag, &bind.name) {
+                Ok(Some(source)) => {
+                    info!("Found {} {:?} mounted at {source:?}", bind.tag, bind.name);
+                    paths_to_bind.push((
+                        Bind {
+                            source,
+                            dest: bind.dest.clone(),
+                        },
+                        false,
+                    ));
+                }
+                Ok(None) => debug!(
+                    "Skipping device with {} {:?}, which isn't there or isn't mounted",
+                    bind.tag, bind.name
+                ),
+                Err(e) => debug!("Skipping device with {} {:?}: {e}", bind.tag, bind.name),
+            }
+        }
+
+        let mut bound = vec![];
+        for (Bind { source, dest }, required) in paths_to_bind {
+            let Some(mount_path) = mount_path_of(&self.mount_dir, &dest) else {
+                warn!("Skipping {dest:?}, which isn't a path below /");
+                continue;
+            };
+
+            if dest == Path::new("/nix") || (self.minimal_dev && dest == Path::new("/dev")) {
+                continue;
+            }
+
+            if !self.probe_exists(&source) {
+                if required {
+                    error!("{source:?} given with --bind does not exist or could not be checked");
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("bind source {source:?} does not exist"),
+                    ));
+                }
+                let level = if explicit {
+                    Level::Debug
+                } else {
+                    self.skip_log_level()
+                };
+                log!(level, "Skipping non-existent or error path {:?}", source);
+                continue;
+            }
+
+            if let Some(parent) = mount_path.parent() {
+                self.create_dir_all(parent)?;
+            }
+            let readonly =
+                readonly_all || self.ro_binds.contains(&dest) || self.is_readonly_kernel_fs(&dest);
+            let mut flags = self.hardening_flags(&dest);
+            if readonly {
+                flags |= MsFlags::MS_RDONLY;
+            }
+            if self.rec_bind_mount(&source, &mount_path, required)? && !flags.is_empty() {
+                self.remount(&mount_path, flags)?;
+            }
+            bound.push(dest);
+        }
+
+        // Read-only paths that aren't bound yet, e.g. below a bound directory
+        for path in &self.ro_binds {
+            if !bound.contains(path) {
+                self.bind_host_readonly(path)?;
+            }
+        }
+
+        // Over the bound directories they are in
+        for path in &self.copies {
+            self.copy_host_file(path)?;
+        }
+        if self.fake_passwd {
+            self.write_fake_passwd(uid, gid)?;
+        }
+
+        // Over the host's /tmp, if it was bound
+        if self.private_tmp {
+            self.mount_private_tmp()?;
+        }
+
+        if self.desktop {
+            for path in self.desktop_paths() {
+                // The private /tmp hides the host's X11 sockets
+                let hidden = self.private_tmp && path.starts_with("/tmp");
+                if hidden || !bound.iter().any(|dest| path.starts_with(dest)) {
+                    self.bind_desktop_path(&path)?;
+                }
+            }
+        }
+
+        // Bind mount /nix from self.nix_to_mount
+        let nix_source = self.nix_source();
+        let mount_path = self.mount_dir.join("nix");
+        self.create_dir_all(&mount_path)?;
+        if self.merge_host_nix {
+            self.mount_merged_nix(nix_source, &mount_path)?;
+        } else if !self.nix_layers.is_empty() {
+            self.mount_nix_layers(nix_source, &mount_path)?;
+        } else if nix_source.is_file() {
+            self.mount_nix_image(nix_source, &mount_path)?;
+        } else {
+            info!("Creating bind mount for /nix from {nix_source:?}");
+            // Bind mounts keep the source path as their name, so label them in the audit log
+            self.audit_note(|| format!("{}: {nix_source:?} -> {mount_path:?}", self.store_label));
+            self.rec_bind_mount(nix_source, &mount_path, true)?;
+        }
 
-/// Test if a file is openable
-fn test_openable() -> Result<bool, nix::Error> {
-    const TEST_FILE: &str = "/dev/megaraid_sas_ioctl_node";
-    let test_file = PathBuf::from(TEST_FILE);
+        if self.host_modules {
+            // Only the modules matching the running kernel are useful
+            let release = uname()?.release().to_owned();
+            self.bind_host_readonly(&Path::new("/lib/modules").join(release))?;
+        }
+        if self.host_firmware {
+            self.bind_host_readonly(Path::new("/lib/firmware"))?;
+        }
 
-    match open(&test_file, OFlag::O_RDONLY, Mode::empty()) {
-        Ok(fd) => {
-            close(fd)?;
-            debug!("Openable test - Success");
-            Ok(true)
+        if self.minimal_dev {
+            self.mount_minimal_dev()?;
         }
-        Err(e) => {
-            error!("Openable test - Error: {e}");
-            Ok(false)
+        if self.unshare_ipc {
+            self.mount_ipc()?;
+        }
+        if self.unshare_cgroup {
+            self.mount_cgroup()?;
         }
-    }
-}
 
-impl AppRun {
-    /// Execute the entrypoint
-    fn exec_in_chroot(mut self) -> Result<(), Box<dyn std::error::Error>> {
-        if !Uid::effective().is_root() {
-            self.new_user_namespace = true;
+        if self.xdg_runtime {
+            self.mount_xdg_runtime(uid)?;
         }
-        self.mounts()?;
-        self.chroot()?;
 
-        // Execute a shell
-        // https://stackoverflow.com/questions/38948669/whats-the-most-direct-way-to-convert-a-path-to-a-c-char
-        let cmd = CString::new(self.entrypoint.as_os_str().to_str().unwrap())?;
-        let args: Vec<CString> = self
-            .args
-            .into_iter()
-            .map(|s| CString::new(s).unwrap())
-            .collect();
-        info!("Executing entrypoint with {:?}", args);
-        execve(&cmd, &args, &[CString::new("TERM=xterm-256color")?])?;
+        if !self.unshare_pid {
+            self.setup_proc()?;
+        }
+
+        for bind in &self.bind_ifs {
+            if !self.probe_exists(&bind.condition) {
+                info!(
+                    "Skipping conditional bind of {:?}: {:?} does not exist",
+                    bind.source, bind.condition
+                );
+                continue;
+            }
+            // The destination was checked when parsing
+            let mount_path = mount_path_of(&self.mount_dir, &bind.dest).unwrap();
+            if let Some(parent) = mount_path.parent() {
+                self.create_dir_all(parent)?;
+            }
+            info!(
+                "Creating conditional bind mount for {:?} at {:?}",
+                bind.source, bind.dest
+            );
+            self.rec_bind_mount(&bind.source, &mount_path, true)?;
+            let flags = self.hardening_flags(&bind.dest);
+            if !flags.is_empty() {
+                self.remount(&mount_path, flags)?;
+            }
+        }
+
+        if self.cwd_mode == CwdMode::Bind {
+            // Only bind the working directory if it isn't visible already
+            let cwd = env::current_dir()?;
+            let mount_path = mount_path_of(&self.mount_dir, &cwd);
+            if let Some(mount_path) = mount_path.filter(|path| !path.exists()) {
+                info!("Creating bind mount for working directory {cwd:?}");
+                self.create_dir_all(&mount_path)?;
+                self.rec_bind_mount(&cwd, &mount_path, true)?;
+                let flags = self.hardening_flags(&cwd);
+                if !flags.is_empty() {
+                    self.remount(&mount_path, flags)?;
+                }
+            }
+        }
+
+        for link in &self.profile_links {
+            self.create_profile_link(link)?;
+        }
+
+        for extra in &self.extra_mounts {
+            self.mount_extra(extra)?;
+        }
 
         Ok(())
     }
 
-    /// Write uid_map and gid_map
-    fn write_id_maps(&self, uid: Uid, gid: Gid) -> Result<(), std::io::Error> {
-        let uid_map: UidMap = UidMap {
-            inside_id: uid,
-            outside_id: uid,
-            count: 1,
+    /// Make a mount from the config file over what is already in the container.
+    /// A missing target is only created on the tmpfs at the root, never in a host directory.
+    fn mount_extra(&self, extra: &config::Mount) -> Result<(), std::io::Error> {
+        // The target was checked when reading the config
+        let mount_path = mount_path_of(&self.mount_dir, &extra.target).unwrap();
+        let fail = |message: String| {
+            error!(
+                "Cannot mount {} at {:?}: {message}",
+                extra.fstype, extra.target
+            );
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cannot mount at {:?}: {message}", extra.target),
+            ))
         };
-        let gid_map = GidMap {
-            inside_id: gid,
-            outside_id: gid,
-            count: 1,
-        };
-        std::fs::write(PathBuf::from("/proc/self/uid_map"), uid_map.to_string())?;
-        info!("Wrote uid_map");
-        std::fs::write(PathBuf::from("/proc/self/setgroups"), "deny")?;
-        std::fs::write(PathBuf::from("/proc/self/gid_map"), gid_map.to_string())?;
-        info!("Wrote gid_map");
+        if !self.dry_run {
+            let existing = mount_path
+                .ancestors()
+                .find(|path| fs::symlink_metadata(path).is_ok())
+                .unwrap();
+            // Mounting follows symlinks, which could lead out of the container
+            if !fs::canonicalize(existing)?.starts_with(fs::canonicalize(&self.mount_dir)?) {
+                return fail(format!("{existing:?} leads out of the container"));
+            }
+            if existing != mount_path {
+                if fs::metadata(existing)?.dev() != fs::metadata(&self.mount_dir)?.dev() {
+                    return fail(format!(
+                        "it doesn't exist, and {existing:?} is bound from the host"
+                    ));
+                }
+                self.create_dir_all(&mount_path)?;
+            }
+        }
 
+        let mut flags = MsFlags::empty();
+        let mut data = vec![];
+        for option in extra.options.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "ro" => flags |= MsFlags::MS_RDONLY,
+                "nosuid" => flags |= MsFlags::MS_NOSUID,
+                "nodev" => flags |= MsFlags::MS_NODEV,
+                "noexec" => flags |= MsFlags::MS_NOEXEC,
+                _ => data.push(option),
+            }
+        }
+        let data = data.join(",");
+        let source = extra.source.as_deref().unwrap_or(&extra.fstype);
+        info!(
+            "Mounting {} from {source:?} at {:?}",
+            extra.fstype, extra.target
+        );
+        self.mount(
+            Some(Path::new(source)),
+            &mount_path,
+            Some(&extra.fstype),
+            flags,
+            Some(&data)
+                .filter(|data| !data.is_empty())
+                .map(String::as_str),
+        )?;
         Ok(())
     }
 
-    /// Find if file exists in a given time
-    fn with_timeout<F, T>(&self, f: F) -> Result<T, mpsc::RecvTimeoutError>
-    where
-        F: FnOnce() -> T,
-        F: Send + 'static,
-        T: Send + 'static,
-    {
-        let (sender, receiver) = mpsc::channel();
-        let _t = thread::spawn(move || {
-            sender.send(f()).unwrap_or(());
-        });
-
-        receiver.recv_timeout(Duration::from_secs_f32(self.mount_timeout))
+    /// Recreate a symlink from the host's root inside the tmpfs, with the same target,
+    /// even if it is broken
+    fn copy_root_symlink(&self, path: &Path) -> Result<(), std::io::Error> {
+        let target = fs::read_link(path)?;
+        let link_path = self.mount_dir.join(path.strip_prefix("/").unwrap());
+        info!("Creating symlink {path:?} -> {target:?}");
+        if self.dry_run {
+            return Ok(());
+        }
+        std::os::unix::fs::symlink(&target, &link_path)
     }
 
-    /// Perform a recursive bind mount
-    fn rec_bind_mount(&self, path: &PathBuf, mount_path: &PathBuf) -> Result<(), std::io::Error> {
-        // https://www.kernel.org/doc/Documentation/filesystems/sharedsubtree.txt
-        let mount_flags = {
-            // Recursively bind mount
-            MsFlags::MS_BIND | MsFlags::MS_REC |
-            // Make this mount point a slave so that mounts in the container don't propagate to the host
-            MsFlags::MS_SLAVE |
-            MsFlags::MS_UNBINDABLE
+    /// Create a profile symlink inside the tmpfs. Below a directory bound from the host,
+    /// like /run, a tmpfs is mounted over that directory first, so the host is left alone.
+    fn create_profile_link(&self, link: &ProfileLink) -> Result<(), std::io::Error> {
+        // The name was checked when parsing
+        let link_path = mount_path_of(&self.mount_dir, &link.name).unwrap();
+
+        // The deepest directory on the way to the link that is already in the container
+        let existing = link_path
+            .ancestors()
+            .skip(1)
+            .find(|dir| fs::symlink_metadata(dir).is_ok())
+            .unwrap();
+        let below = link_path.strip_prefix(existing).unwrap();
+        let fail = |message: String| {
+            error!("Cannot create profile link {:?}: {message}", link.name);
+            Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("cannot create profile link {:?}: {message}", link.name),
+            ))
         };
-        let path_name = path.file_name().unwrap();
+        // Following a symlink could lead anywhere on the host
+        if !fs::symlink_metadata(existing)?.is_dir() {
+            return fail(format!("{existing:?} is not a directory"));
+        }
 
-        let mount_result = if path.is_dir() {
-            // Create bind mount
-            debug!("Creating bind mount for {path_name:?}");
-            fs::create_dir_all(mount_path)?;
-            mount::<_, _, Path, Path>(Some(path), mount_path, None, mount_flags, None)
+        let on_root_tmpfs = fs::metadata(existing)?.dev() == fs::metadata(&self.mount_dir)?.dev();
+        if on_root_tmpfs {
+            if fs::symlink_metadata(&link_path).is_ok() {
+                return fail(format!("{link_path:?} already exists in the container"));
+            }
         } else {
-            // Create a file and bind mount it
-            debug!("Creating bind mount for {path_name:?}");
-            fs::write(mount_path, "")?;
-            mount::<_, _, Path, Path>(Some(path), mount_path, None, mount_flags, None)
-        };
+            let skip = below.components().next().unwrap().as_os_str();
+            self.shadow_host_dir(existing, skip)?;
+        }
 
-        if let Err(e) = mount_result {
-            warn!("Failed to mount {path_name:?}: {e:?}");
+        if let Some(parent) = link_path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        info!("Creating profile link {:?} -> {:?}", link.name, link.target);
+        if self.dry_run {
+            return Ok(());
         }
+        std::os::unix::fs::symlink(&link.target, &link_path)
+    }
+
+    /// Mount a tmpfs over dir, a directory bound from the host, and bind the host's entries
+    /// back into it, except skip, so that new entries can be created in it
+    fn shadow_host_dir(&self, dir: &Path, skip: &OsStr) -> Result<(), std::io::Error> {
+        // Keeps the covered directory reachable, to bind its entries from
+        let covered = fs::File::open(dir)?;
+        let covered_path = PathBuf::from(format!("/proc/self/fd/{}", covered.as_raw_fd()));
+        let mode = fs::metadata(dir)?.permissions().mode() & 0o7777;
 
+        info!("Mounting tmpfs over {dir:?} to create new entries in it");
+        self.mount(
+            Some(Path::new("tmpfs")),
+            dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(&format!("mode={mode:o}")),
+        )?;
+        for entry in fs::read_dir(&covered_path)? {
+            let entry = entry?;
+            if entry.file_name() == skip {
+                continue;
+            }
+            let mount_path = dir.join(entry.file_name());
+            if entry.file_type()?.is_symlink() {
+                if !self.dry_run {
+                    std::os::unix::fs::symlink(fs::read_link(entry.path())?, &mount_path)?;
+                }
+                continue;
+            }
+            self.rec_bind_mount(&entry.path(), &mount_path, false)?;
+        }
         Ok(())
     }
 
-    /// Mount all nonexist subdirectories of /nix/store from host
-    fn mount_nix(&self, host_nix: &Path, mount_nix: &Path) -> Result<(), std::io::Error> {
-        let host_store = host_nix.join("store");
-        let mount_store = mount_nix.join("store");
-        if !host_store.exists() {
-            return Ok(());
-        }
-        if !mount_store.exists() {
-            fs::create_dir_all(&mount_store)?;
+    /// The directory mounted at /nix: the bundled one,
+    /// or the host's if the bundle has no store and we were asked to use the host's
+    fn nix_source(&self) -> &Path {
+        if self.merge_host_store && !self.nix_dir.is_file() && !self.nix_dir.join("store").is_dir()
+        {
+            Path::new("/nix")
+        } else {
+            &self.nix_dir
         }
+    }
 
-        info!("Mounting {host_store:?}/* to {mount_store:?}");
-        for entry in host_store.read_dir()? {
-            let path = entry?.path();
-            if !path.is_dir() {
+    /// Check that the profile link targets exist in the store that is mounted at /nix
+    fn check_profile_links(&self) -> Result<(), std::io::Error> {
+        if self.nix_source().is_file() {
+            debug!("The bundled /nix is an image, not checking profile links before mounting it");
+            return Ok(());
+        }
+        for link in &self.profile_links {
+            let relative = link.target.strip_prefix("/nix").unwrap();
+            let exists = |nix: &Path| fs::symlink_metadata(nix.join(relative)).is_ok();
+            // With --merge-host-nix, the target may come from the host's store instead
+            if exists(self.nix_source()) || (self.merge_host_nix && exists(Path::new("/nix"))) {
                 continue;
             }
+            error!(
+                "profile link target {:?} is not in the nix store mounted at /nix",
+                link.target
+            );
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("profile link target {:?} does not exist", link.target),
+            ));
+        }
+        Ok(())
+    }
 
-            // Check if this directory exists in the container
-            let mount_path = mount_store.join(path.file_name().unwrap());
-            if mount_path.exists() {
-                continue;
+    /// Check that the entrypoint resolves to an executable file, and so does the interpreter
+    /// if it is a script, so that a broken bundle fails with a clear error before setting up
+    /// the container rather than with an errno from execve(2)
+    fn check_entrypoint(&self) -> Result<(), std::io::Error> {
+        if self.nix_source().is_file() {
+            debug!("The bundled /nix is an image, not checking the entrypoint before mounting it");
+            return Ok(());
+        }
+        let resolve = |what: &str, path: &Path| {
+            let mut target = store::resolve(self.nix_source(), path, self.max_symlink_depth);
+            if self.merge_host_nix && target.is_err() {
+                // The path may come from the host's store instead
+                target = store::resolve(Path::new("/nix"), path, self.max_symlink_depth);
             }
+            let target = target.map_err(|e| {
+                error!("The {what} {path:?} does not resolve to an existing file: {e}");
+                e
+            })?;
+            if !is_executable(&target) {
+                error!(
+                    "The {what} {path:?} resolves to {target:?}, which is not an executable file"
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("{what} {path:?} is not executable"),
+                ));
+            }
+            debug!("The {what} {path:?} resolves to {target:?}");
+            Ok(target)
+        };
 
-            // Create a bind mount
-            self.rec_bind_mount(&path, &mount_path)?;
+        let target = resolve("entrypoint", &self.entrypoint)?;
+        if let Some(interpreter) = script_interpreter(&target)? {
+            // A relative interpreter is looked up from the working directory, which changes
+            if interpreter.is_absolute() {
+                resolve("interpreter", &interpreter)?;
+            }
         }
-
         Ok(())
     }
 
-    /// Create a new mount namespace, bind mount everything from / into the mount_dir,
-    /// and bind mount /nix from self.nix_to_mount
-    fn mounts(&self) -> Result<(), std::io::Error> {
-        let (uid, gid) = (Uid::current(), Gid::current());
-        debug!("Current uid: {uid}, gid: {gid}");
+    /// Warn about the preflight paths that can be opened on the host, but not in the container
+    fn preflight(&self) {
+        if self.preflight_paths.is_empty() {
+            return;
+        }
+        let results = preflight::check(&self.mount_dir, &self.preflight_paths);
+        for result in &results {
+            debug!("Preflight: {result:?}");
+            if let (true, Err(e)) = (result.is_lost(), result.in_container) {
+                warn!(
+                    "{:?} won't be accessible inside the container: {e}",
+                    result.path
+                );
+            }
+        }
+        let lost = results.iter().filter(|result| result.is_lost()).count();
+        info!(
+            "Preflight checked {} paths, {lost} of them are only accessible on the host",
+            results.len()
+        );
+    }
 
-        // Create a new mount namespace
-        let clone_flags = if self.new_user_namespace {
-            CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS
+    /// Derive a stable hostname from the store path the entrypoint resolves to,
+    /// e.g. `hello-2-12-1-q9cqc10s`
+    fn bundle_hostname(&self) -> Result<String, std::io::Error> {
+        const HASH_LEN: usize = 8;
+
+        let nix_source = self.nix_source();
+        let target = store::resolve(nix_source, &self.entrypoint, self.max_symlink_depth)?;
+        let store_path =
+            store::store_path_of(&nix_source.join("store"), &target).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("entrypoint {:?} is not in the nix store", self.entrypoint),
+                )
+            })?;
+
+        let (hash, name) = store_path.split_once('-').unwrap_or((&store_path, ""));
+        let hash = hash.get(..HASH_LEN).unwrap_or(hash);
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .take(MAX_HOSTNAME_LEN - HASH_LEN - 1)
+            .collect();
+        let name = name.trim_matches('-');
+
+        Ok(if name.is_empty() {
+            hash.to_string()
         } else {
-            CloneFlags::CLONE_NEWNS
+            format!("{name}-{hash}")
+        })
+    }
+
+    /// Register the store path of the entrypoint as a GC root of the host nix, if there is one
+    fn register_gc_root(&self) {
+        let user = User::from_uid(Uid::current())
+            .ok()
+            .flatten()
+            .map(|user| user.name);
+        let Some(roots_dir) = gc_root::roots_dir(user.as_deref()) else {
+            info!("No gcroots directory on the host, not registering a GC root");
+            return;
         };
-        info!("Creating new mount namespace with {clone_flags:?}");
-        if let Err(e) = unshare(clone_flags) {
-            if !self.new_user_namespace {
-                error!("Failed to create new mount namespace: {e:?}. Did you forget to run me as root?");
-            } else {
-                error!("Failed to create new mount namespace: {e:?}.");
-            }
+
+        let nix_source = self.nix_source();
+        let store_path = store::resolve(nix_source, &self.entrypoint, self.max_symlink_depth)
+            .ok()
+            .and_then(|target| store::store_path_of(&nix_source.join("store"), &target));
+        let Some(store_path) = store_path else {
+            warn!(
+                "Entrypoint {:?} is not in the nix store, not registering a GC root",
+                self.entrypoint
+            );
+            return;
+        };
+
+        match gc_root::register(&roots_dir, &Path::new("/nix/store").join(store_path)) {
+            Ok(root) => info!("Registered GC root {root:?}"),
+            Err(e) => warn!("Failed to register GC root in {roots_dir:?}: {e}"),
         }
+    }
 
-        if clone_flags.contains(CloneFlags::CLONE_NEWUSER) {
-            info!("Created new user namespace");
-            self.write_id_maps(uid, gid)?;
+    /// Check that the entrypoint and the store paths it references exist.
+    /// Without a nix database, only the direct references of the entrypoint are checked.
+    fn probe_store(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let nix_source = self.nix_source();
+        info!(
+            "Probing store {nix_source:?} for the dependencies of {:?}",
+            self.entrypoint
+        );
+
+        let target = match store::resolve(nix_source, &self.entrypoint, self.max_symlink_depth) {
+            Ok(target) => target,
+            Err(e) => {
+                error!(
+                    "Entrypoint {:?} does not resolve to an existing file: {e}",
+                    self.entrypoint
+                );
+                return Err(Box::new(e));
+            }
+        };
+        debug!("Entrypoint resolves to {target:?}");
+
+        let missing: Vec<String> = store::references(&fs::read(&target)?)
+            .into_iter()
+            .filter(|name| fs::symlink_metadata(nix_source.join("store").join(name)).is_err())
+            .collect();
+        if !missing.is_empty() {
+            for name in &missing {
+                error!("Missing store path /nix/store/{name}");
+            }
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{} store paths referenced by the entrypoint are missing",
+                    missing.len()
+                ),
+            )));
         }
 
-        // Mark all mount points as slave
-        // So that mounts in the container don't propagate to the host
-        // For example, when we unmount /nix in the container, we don't want that to propagate to the host
-        info!("Mounting / as rslave");
-        mount(
-            None::<&str>,
-            "/",
-            None::<&str>,
-            MsFlags::MS_SLAVE | MsFlags::MS_REC,
-            None::<&str>,
-        )?;
+        info!("All store paths referenced by the entrypoint exist");
+        Ok(())
+    }
 
-        // Mount a tmpfs
-        info!("Mounting tmpfs to {:?}", self.mount_dir);
-        mount(
-            Some("tmpfs"),
-            &self.mount_dir,
-            Some("tmpfs"),
-            MsFlags::MS_NOSUID,
-            Some("mode=755"),
+    /// Make mount_dir the root with pivot_root(2), and detach the old root, so that
+    /// unlike with chroot(2), the host's root isn't reachable from the container anymore.
+    /// Returns false if pivot_root itself failed, and nothing was changed.
+    fn pivot_root(&self) -> Result<bool, std::io::Error> {
+        const OLD_ROOT: &str = "/.apprun/old-root";
+
+        info!("Pivoting root to {:?}", self.mount_dir);
+        let put_old = self.mount_dir.join(OLD_ROOT.strip_prefix('/').unwrap());
+        fs::create_dir_all(&put_old)?;
+        let result = self.audit(
+            || format!("pivot_root({:?}, {put_old:?})", self.mount_dir),
+            || retry_on_eintr(|| nix::unistd::pivot_root(&self.mount_dir, &put_old)),
+        );
+        if let Err(e) = result {
+            warn!("pivot_root failed ({e}), falling back to chroot");
+            let _ = fs::remove_dir(&put_old);
+            return Ok(false);
+        }
+
+        env::set_current_dir("/")?;
+        self.audit(
+            || format!("umount2({OLD_ROOT:?}, MNT_DETACH)"),
+            || retry_on_eintr(|| umount2(OLD_ROOT, MntFlags::MNT_DETACH)),
         )?;
+        fs::remove_dir(OLD_ROOT)?;
+        Ok(true)
+    }
 
-        let mut paths_to_bind = vec![];
-        if let Some(binds) = self.binds.as_ref() {
-            // Bind mount everything from / into the mount_dir
-            for bind in binds {
-                let path = PathBuf::from(bind);
-                paths_to_bind.push(path);
+    /// Detach the tmpfs at mount_dir and everything below it once the entrypoint has exited,
+    /// and remove what AppRun created on the host. The mounts go away with the namespace
+    /// anyway, so unmounting only matters for a mount directory given with --mount-dir.
+    fn cleanup(&self) {
+        if let Some(links) = &self.ns_links {
+            self.remove_ns_links(links);
+        }
+        if let Some(pidfile) = &self.opened_pidfile {
+            if let Err(e) = self.audit(
+                || format!("unlinkat({:?})", pidfile.name),
+                || {
+                    unlinkat(
+                        Some(pidfile.dir.as_raw_fd()),
+                        pidfile.name.as_os_str(),
+                        UnlinkatFlags::NoRemoveDir,
+                    )
+                },
+            ) {
+                warn!("Failed to remove pidfile {:?}: {e}", pidfile.name);
             }
-        } else {
-            // Copy over root directories
-            let files = fs::read_dir("/")?;
-            for file in files {
-                let path = file?.path();
-                paths_to_bind.push(path);
+        }
+        if !self.explicit_mount_dir {
+            return;
+        }
+        info!("Unmounting {:?}", self.mount_dir);
+        match self.audit(
+            || format!("umount2({:?}, MNT_DETACH)", self.mount_dir),
+            || retry_on_eintr(|| umount2(&self.mount_dir, MntFlags::MNT_DETACH)),
+        ) {
+            Ok(()) => {}
+            // pivot_root(2) moved AppRun's root too, so the mount is gone along with the old root,
+            // and the path is missing or not a mount point in the container
+            Err(Errno::ENOENT | Errno::EINVAL) => {
+                debug!("{:?} was pivoted to, not unmounting it", self.mount_dir)
             }
+            Err(e) => warn!("Failed to unmount {:?}: {e}", self.mount_dir),
         }
 
-        for path in paths_to_bind {
-            let path_name = path.file_name().unwrap();
-            let mount_path = self.mount_dir.join(path_name);
+        if let Some(created) = &self.created_mount_dir {
+            self.remove_created_dir(created);
+        }
+    }
 
-            if path_name == "nix" {
-                continue;
+    /// Remove the directories in created, innermost first, since each one is only empty
+    /// once the one in it is gone
+    fn remove_created_dir(&self, created: &CreatedDir) {
+        for relative in created
+            .relative
+            .ancestors()
+            .take_while(|relative| !relative.as_os_str().is_empty())
+        {
+            debug!("Removing created directory {relative:?}");
+            if let Err(e) = self.audit(
+                || format!("unlinkat({relative:?}, AT_REMOVEDIR)"),
+                || {
+                    unlinkat(
+                        Some(created.parent.as_raw_fd()),
+                        relative,
+                        UnlinkatFlags::RemoveDir,
+                    )
+                },
+            ) {
+                warn!("Failed to remove created directory {relative:?}: {e}");
+                break;
             }
+        }
+    }
 
-            let check_path = path.clone();
-            let exists = match self.with_timeout(move || check_path.try_exists()) {
-                Err(e) => {
-                    warn!("Error: {}", e.to_string());
-                    warn!("Timed out to check existance of {path_name:?}. Maybe it's a broken symlink or broken NFS mount?");
-                    false
-                }
-                Ok(Err(e)) => {
-                    warn!("Error: {}", e.to_string());
-                    warn!("Failed to check existance of {path_name:?}.");
-                    false
-                }
-                Ok(Ok(exists)) => exists,
-            };
+    /// Link the namespaces AppRun created from dir, so that other processes can enter them
+    /// with setns(2), e.g. with nsenter --mount=DIR/mnt. The links point into /proc of
+    /// AppRun, so they only work while it runs.
+    fn write_ns_links(&self, dir: &Path) -> Result<NsLinks, std::io::Error> {
+        let created = if dir.exists() {
+            None
+        } else {
+            Some(CreatedDir::create(dir)?)
+        };
+        let mut clone_flags = self.clone_flags();
+        if self.unshare_pid {
+            clone_flags |= CloneFlags::CLONE_NEWPID;
+        }
 
-            if !exists {
-                warn!("Skipping non-existent or error path {:?}", path);
+        let pid = getpid();
+        let mut names = vec![];
+        for (flag, name, ns) in NS_LINKS {
+            if !clone_flags.contains(flag) {
                 continue;
             }
-
-            self.rec_bind_mount(&path, &mount_path)?;
+            let link = dir.join(name);
+            // Left behind by a run that didn't exit cleanly
+            match fs::remove_file(&link) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            let target = format!("/proc/{pid}/ns/{ns}");
+            debug!("Linking {link:?} to {target:?}");
+            std::os::unix::fs::symlink(&target, &link)?;
+            names.push(name);
         }
+        info!("Linked the namespaces of the app from {dir:?}");
+        Ok(NsLinks {
+            dir: fs::File::open(dir)?,
+            names,
+            created,
+        })
+    }
 
-        // Bind mount /nix from self.nix_to_mount
-        let mount_path = self.mount_dir.join("nix");
-        fs::create_dir_all(&mount_path)?;
-        info!("Creating bind mount for /nix from {:?}", self.nix_dir);
-        self.rec_bind_mount(&self.nix_dir, &mount_path)?;
-
-        Ok(())
+    /// Remove the links written by write_ns_links, and the directory if it was created
+    fn remove_ns_links(&self, links: &NsLinks) {
+        for name in &links.names {
+            if let Err(e) = self.audit(
+                || format!("unlinkat({name:?})"),
+                || {
+                    unlinkat(
+                        Some(links.dir.as_raw_fd()),
+                        *name,
+                        UnlinkatFlags::NoRemoveDir,
+                    )
+                },
+            ) {
+                warn!("Failed to remove namespace link {name:?}: {e}");
+            }
+        }
+        if let Some(created) = &links.created {
+            self.remove_created_dir(created);
+        }
     }
 
     /// Chroot to self.mount_dir
     fn chroot(&self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Chrooting to {:?}", self.mount_dir);
-
         // Save working directory
         let current_dir: PathBuf = env::current_dir()?;
-        // Chroot
-        chroot(&self.mount_dir)?;
-        // Switch back to working directory
-        env::set_current_dir(current_dir)?;
+
+        if self.use_chroot || !self.pivot_root()? {
+            info!("Chrooting to {:?}", self.mount_dir);
+            self.audit(
+                || format!("chroot({:?})", self.mount_dir),
+                || retry_on_eintr(|| chroot(&self.mount_dir)),
+            )?;
+        }
+
+        // Switch to the working directory given with --chdir, or chosen by cwd_mode
+        let target = match (&self.chdir, self.cwd_mode) {
+            // Relative paths are taken from the container root
+            (Some(dir), _) => Path::new("/").join(dir),
+            (None, CwdMode::Root) => PathBuf::from("/"),
+            (None, CwdMode::App) => self.app_dir.clone(),
+            (None, CwdMode::Preserve | CwdMode::PreserveOrRoot | CwdMode::Bind) => current_dir,
+        };
+        if let Err(e) = env::set_current_dir(&target) {
+            if self.chdir.is_some() || self.cwd_mode != CwdMode::PreserveOrRoot {
+                error!("Failed to change to {target:?} inside the container: {e}");
+                return Err(Box::new(e));
+            }
+            warn!("{target:?} is not available inside the container ({e}), changing to /");
+            env::set_current_dir("/")?;
+        }
+        debug!("Working directory is {:?}", env::current_dir()?);
 
         Ok(())
     }
 }
 
+/// Create a directory only the current user can access, or check that an existing one is private
+fn create_private_dir(path: &Path) -> Result<(), std::io::Error> {
+    match fs::DirBuilder::new().mode(0o700).create(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        result => return result,
+    }
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir()
+        || metadata.uid() != Uid::current().as_raw()
+        || metadata.mode() & 0o077 != 0
+    {
+        error!("{path:?} is not a directory owned by the current user with mode 700");
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{path:?} is not a private directory"),
+        ));
+    }
+    Ok(())
+}
+
+/// Fill in the settings of config that weren't given on the command line
+fn apply_config(cli: &mut Cli, config: config::Config) -> Result<(), std::io::Error> {
+    if cli.bind.is_none() {
+        cli.bind = config
+            .binds
+            .map(|binds| binds.iter().map(|bind| bind.parse()).collect())
+            .transpose()
+            .map_err(|e: String| {
+                error!("Invalid bind in config: {e}");
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+    }
+    cli.nix_dir = cli.nix_dir.take().or(config.nix_dir);
+    cli.entrypoint = cli.entrypoint.take().or(config.entrypoint);
+    cli.mount_timeout = cli.mount_timeout.or(config.mount_timeout);
+    // --setenv comes later, so that it wins
+    let mut setenv: Vec<_> = config
+        .env
+        .into_iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    setenv.append(&mut cli.setenv);
+    cli.setenv = setenv;
+    if config.unshare_pid && cli.exec_replace {
+        error!("unshare_pid in the config file cannot be used with --apprun-exec-replace");
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "unshare_pid conflicts with --exec-replace",
+        ));
+    }
+    cli.unshare_pid |= config.unshare_pid;
+    cli.unshare_net |= config.unshare_net;
+    cli.unshare_uts |= config.unshare_uts;
+    if cli.hostname.is_none() {
+        cli.hostname = config
+            .hostname
+            .map(|name| parse_hostname(&name))
+            .transpose()
+            .map_err(|e| {
+                error!("Invalid hostname in config: {e}");
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+
     // Oply keep --apprun-xxx flags and replace that with --xxx
 
     let mut args = std::env::args();
     let arg0 = args.next().unwrap_or_else(|| "nix-apprun".to_string());
 
+    let apprun_name = arg0.clone();
     let mut apprun_args = vec![arg0.clone()];
     let mut pass_args = vec![arg0];
+    let args: Vec<_> = args.collect();
+    // A lone --version is a version query, with anything else it's meant for the app
+    if args == ["--version"] {
+        println!("nix-apprun v{}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
     for arg in args {
         if arg.starts_with("--apprun-") {
             apprun_args.push(arg.replace("--apprun-", "--"));
@@ -325,45 +3507,216 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // let cli = Cli::parse();
-    let cli = Cli::parse_from(apprun_args);
+    let mut cli = Cli::parse_from(apprun_args);
+    if cli.exec_replace && cli.unshare.contains(&Namespace::Pid) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--unshare=pid cannot be used with --exec-replace",
+            )
+            .exit();
+    }
+    for namespace in &cli.unshare {
+        match namespace {
+            Namespace::Mount => {}
+            Namespace::Pid => cli.unshare_pid = true,
+            Namespace::Net => cli.unshare_net = true,
+            Namespace::Uts => cli.unshare_uts = true,
+            Namespace::Ipc => cli.unshare_ipc = true,
+            Namespace::Cgroup => cli.unshare_cgroup = true,
+        }
+    }
 
     if cli.version {
         println!("nix-apprun v{}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
     }
 
-    env_logger::init();
+    let mut level = match cli.verbose {
+        _ if cli.quiet => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    if cli.dry_run && !cli.quiet {
+        // The plan is logged at info level, so show it unless asked otherwise
+        level = level.max(LevelFilter::Info);
+    }
+    // RUST_LOG still wins over the level given by flags
+    let mut logger = env_logger::Builder::new();
+    logger
+        .filter_level(level)
+        .parse_env(env_logger::Env::default());
+    if cli.log_format == LogFormat::Json {
+        logger.format(json_log::format);
+    }
+    logger.init();
 
     let current_exe = env::current_exe()?;
     let current_dir = current_exe.parent().unwrap();
     info!("Current directory: {:?}", current_dir);
 
+    let config_path = match &cli.config {
+        Some(path) => Some(std::path::absolute(path)?),
+        None => Some(current_dir.join("apprun.toml")).filter(|path| path.exists()),
+    };
+    let mut extra_mounts = vec![];
+    if let Some(path) = config_path {
+        debug!("Reading config from {path:?}");
+        let config = config::Config::read(&path).map_err(|e| {
+            error!("Failed to read config {path:?}: {e}");
+            e
+        })?;
+        // A symlink to AppRun named after a tool selects that tool's profile
+        let name = Path::new(&apprun_name).file_name().unwrap_or_default();
+        let name = name.to_string_lossy();
+        if config.profiles.contains_key(&*name) {
+            debug!("Using the profile {name:?} of the config");
+        }
+        let mut config = config.for_name(&name);
+        extra_mounts = config.mounts.take().unwrap_or_default();
+        if let Some(extra) = extra_mounts
+            .iter()
+            .find(|extra| mount_path_of(Path::new("/"), &extra.target).is_none())
+        {
+            error!(
+                "Invalid mount in config {path:?}: target {:?} must be an absolute path below /",
+                extra.target
+            );
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "mount target {:?} must be an absolute path below /",
+                    extra.target
+                ),
+            )));
+        }
+        apply_config(&mut cli, config)?;
+    }
+
+    // Explicit relative paths are resolved against the working directory now,
+    // since it changes once we chroot
     let nix_dir = if let Some(nix_dir) = cli.nix_dir {
-        nix_dir
+        std::path::absolute(nix_dir)?
     } else {
         current_dir.join("nix")
     };
-    if !nix_dir.exists() {
-        error!("nix directory does not exist");
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "nix directory does not exist",
-        )));
+    if nix_dir.is_file() {
+        if !loop_dev::is_squashfs(&nix_dir)? {
+            error!("{nix_dir:?} is neither a directory nor a squashfs image");
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "nix image is not a squashfs image",
+            )));
+        }
+        if cli.merge_host_nix || cli.probe_store {
+            error!("--apprun-merge-host-nix and --apprun-probe-store need a nix directory, not an image");
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "nix image given with an option that needs a nix directory",
+            )));
+        }
+    } else if !nix_dir.join("store").is_dir() {
+        if !cli.merge_host_store {
+            let message = if nix_dir.exists() {
+                "nix directory does not contain a store"
+            } else {
+                "nix directory does not exist"
+            };
+            error!(
+                "{message}, pass --apprun-merge-host-store to use the host's /nix/store instead"
+            );
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                message,
+            )));
+        }
+        if !Path::new("/nix/store").is_dir() {
+            error!("Neither the bundled nor the host nix store exists");
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no nix store to mount",
+            )));
+        }
+        warn!("Bundled nix store {nix_dir:?} is missing, using the host's /nix/store");
+    }
+    let nix_layers: Vec<_> = cli
+        .nix_layer
+        .into_iter()
+        .map(std::path::absolute)
+        .collect::<Result<_, _>>()?;
+    for layer in &nix_layers {
+        let valid = if layer.is_file() {
+            loop_dev::is_squashfs(layer)?
+        } else {
+            layer.is_dir()
+        };
+        if !valid {
+            error!("Nix layer {layer:?} is neither a directory nor a squashfs image");
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("nix layer {layer:?} is not a directory or squashfs image"),
+            )));
+        }
     }
 
+    let explicit_mount_dir = cli.mount_dir.is_some();
     let mount_dir = if let Some(mount_dir) = cli.mount_dir {
-        mount_dir
+        std::path::absolute(mount_dir)?
     } else {
-        current_dir.join("mountroot")
+        let mount_dir = current_dir.join("mountroot");
+        if mount_dir.exists() {
+            mount_dir
+        } else {
+            // The AppDir may be read-only, so it can't be created there
+            let fallback = env::temp_dir().join(format!("apprun-mountroot-{}", Uid::current()));
+            info!("{mount_dir:?} does not exist, using {fallback:?} as mount directory");
+            create_private_dir(&fallback)?;
+            fallback
+        }
     };
+    let mut created_mount_dir = None;
     if !mount_dir.exists() {
-        error!("mount directory does not exist");
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "mount directory does not exist",
-        )));
+        if !cli.mkdir {
+            error!("mount directory does not exist, pass --apprun-mkdir to create it");
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "mount directory does not exist",
+            )));
+        }
+        if cli.dry_run || cli.print_config {
+            info!("Would create mount directory {mount_dir:?}");
+        } else {
+            info!("Creating mount directory {mount_dir:?}");
+            created_mount_dir = Some(CreatedDir::create(&mount_dir).inspect_err(|e| {
+                error!("Failed to create mount directory {mount_dir:?}: {e}");
+            })?);
+        }
     }
 
-    let entrypoint = if let Some(entrypoint) = cli.entrypoint {
+    // Where the arguments given to AppRun start, after argv[0] or the words of the command
+    let mut user_args_start = 1;
+    let entrypoint = if let Some(command) = &cli.entrypoint_shell_words {
+        let mut words = shell_words::split(command).map_err(|e| {
+            error!("Invalid entrypoint command: {e}");
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+        })?;
+        if words.is_empty() {
+            error!("Entrypoint command is empty");
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "entrypoint command is empty",
+            )));
+        }
+        // The words replace argv[0], and the arguments given to AppRun follow them
+        user_args_start = words.len();
+        words.extend(pass_args.drain(1..));
+        pass_args = words;
+        PathBuf::from(&pass_args[0])
+    } else if let Some(name) = &cli.exec_search {
+        name.clone()
+    } else if let Some(entrypoint) = cli.entrypoint {
         entrypoint
     } else {
         let entrypoint = current_dir.join("entrypoint");
@@ -375,16 +3728,184 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         entrypoint
     };
 
+    pass_args.splice(user_args_start..user_args_start, cli.entrypoint_arg);
+    // Like execvp(3), a name without a slash is a command to search for in PATH
+    let exec_search =
+        cli.exec_search.is_some() || !entrypoint.as_os_str().as_bytes().contains(&b'/');
+
+    let snapshot = cli.snapshot.map(std::path::absolute).transpose()?;
+    let preflight_paths = if !cli.preflight {
+        vec![]
+    } else if cli.preflight_path.is_empty() {
+        preflight::DEFAULT_PATHS.map(PathBuf::from).to_vec()
+    } else {
+        cli.preflight_path
+            .into_iter()
+            .map(std::path::absolute)
+            .collect::<Result<_, _>>()?
+    };
+
     let app = AppRun {
         mount_dir,
+        explicit_mount_dir,
+        created_mount_dir,
         nix_dir,
         entrypoint,
         args: pass_args,
         binds: cli.bind,
-        mount_timeout: cli.mount_timeout,
+        bind_tries: cli.bind_try,
+        mount_timeout: cli.mount_timeout.unwrap_or(5.0),
+        bind_timeouts: cli
+            .bind_timeout
+            .into_iter()
+            .map(|(path, seconds)| Ok((std::path::absolute(path)?, seconds)))
+            .collect::<Result<_, std::io::Error>>()?,
+        allow_setgroups: cli.allow_setgroups,
+        map_root: cli.map_root,
+        profile_links: cli.profile_link,
+        bind_ifs: cli.bind_if,
+        label_binds: cli.bind_try_label,
+        disk_dir: cli.disk_dir,
+        dump_env: cli.dump_env,
+        env_from_dump: cli.env_from_dump,
+        merge_host_store: cli.merge_host_store,
+        merge_host_nix: cli.merge_host_nix,
+        nix_layers,
+        limit_mounts: cli.limit_mounts,
+        quiet_skips: cli.quiet_skips,
+        hostname_from_bundle: cli.hostname_from_bundle,
+        unshare_uts: cli.unshare_uts,
+        hostname: cli.hostname,
+        max_symlink_depth: cli.max_symlink_depth,
+        host_modules: cli.host_modules,
+        host_firmware: cli.host_firmware,
+        cwd_mode: cli.cwd_mode,
+        chdir: cli.chdir,
+        audit: cli.audit,
+        dry_run: cli.dry_run,
+        register_gc_root: cli.register_gc_root,
+        readonly_bind_all: cli.readonly_bind_all,
+        snapshot,
+        snapshot_depth: cli.snapshot_depth,
+        store_label: cli.store_label,
+        no_slave_root: cli.no_slave_root,
+        proc_hidepid: cli.proc_hidepid,
+        proc_mask: cli.proc_mask,
+        rw_sys: cli.rw_sys,
+        nosuid: cli.nosuid,
+        nodev: cli.nodev,
+        copy_entrypoint: cli.copy_entrypoint,
+        term: cli.term,
+        xdg_runtime: cli.xdg_runtime,
+        desktop: cli.desktop,
+        verify_propagation: cli.verify_propagation,
+        exec_search,
+        metrics_file: cli.metrics_file.map(std::path::absolute).transpose()?,
+        started: Some(started),
+        exec_replace: cli.exec_replace,
+        forward_signals: cli.forward_signal,
+        wait_children: cli.wait_children.map(Duration::from_secs_f32),
+        signal_on_oom: cli.signal_on_oom,
+        extra_mounts,
+        clearenv: cli.clearenv,
+        setenv: cli.setenv,
+        unsetenv: cli.unsetenv,
+        use_chroot: cli.use_chroot,
+        unshare_pid: cli.unshare_pid,
+        unshare_net: cli.unshare_net,
+        unshare_ipc: cli.unshare_ipc,
+        unshare_cgroup: cli.unshare_cgroup,
+        minimal_dev: cli.minimal_dev,
+        ro_binds: cli
+            .ro_bind
+            .into_iter()
+            .map(std::path::absolute)
+            .collect::<Result<_, _>>()?,
+        copies: cli
+            .copy
+            .into_iter()
+            .map(std::path::absolute)
+            .collect::<Result<_, _>>()?,
+        fake_passwd: cli.fake_passwd,
+        keep_caps: cli.drop_caps.then_some(cli.cap_add),
+        excludes: cli.exclude,
+        private_tmp: cli.private_tmp,
+        tmpfs_size: cli.tmpfs_size,
+        preflight_paths,
+        ns_fd_path: cli.ns_fd_path.map(std::path::absolute).transpose()?,
+        pidfile: cli.pidfile.map(std::path::absolute).transpose()?,
+        app_dir: current_dir.to_path_buf(),
+        new_user_namespace: !Uid::effective().is_root(),
         ..Default::default()
     };
+    if cli.print_config {
+        println!("{}", app.config_json());
+        return Ok(());
+    }
+    if cli.probe_store {
+        app.probe_store()?;
+    }
+    if cli.daemonize {
+        daemon::daemonize(cli.umask.unwrap_or(Mode::from_bits_truncate(0o022)))?;
+    } else if let Some(mask) = cli.umask {
+        umask(mask);
+    }
     app.exec_in_chroot()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_paths_stay_below_mount_dir() {
+        let mount_dir = Path::new("/tmp/mountroot");
+        let mount_path = |path: &str| mount_path_of(mount_dir, Path::new(path));
+        assert_eq!(
+            mount_path("/usr/lib"),
+            Some(PathBuf::from("/tmp/mountroot/usr/lib"))
+        );
+        assert_eq!(
+            mount_path("/usr/./lib/"),
+            Some(PathBuf::from("/tmp/mountroot/usr/lib"))
+        );
+        for path in ["/", "/foo/..", "/foo/../etc", "usr", ""] {
+            assert_eq!(mount_path(path), None, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn retries_interrupted_calls_a_bounded_number_of_times() {
+        let mut calls = 0;
+        let result = retry_on_eintr(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(Errno::EINTR)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        calls = 0;
+        let result: nix::Result<()> = retry_on_eintr(|| {
+            calls += 1;
+            Err(Errno::EINTR)
+        });
+        assert_eq!(result, Err(Errno::EINTR));
+        assert_eq!(calls, EINTR_RETRIES + 1);
+    }
+
+    #[test]
+    fn parses_bind_timeouts() {
+        assert_eq!(
+            parse_bind_timeout("/mnt/a=b=2.5"),
+            Ok((PathBuf::from("/mnt/a=b"), 2.5))
+        );
+        for s in ["/mnt", "/mnt=", "/mnt=-1", "/mnt=inf", "/mnt=NaN"] {
+            assert!(parse_bind_timeout(s).is_err(), "{s:?}");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A line of /proc/self/mountinfo, with the fields AppRun needs
+#[derive(Debug)]
+pub struct Mount {
+    /// Major and minor number of the mounted device
+    pub device: (u64, u64),
+    /// The directory of the filesystem that is mounted, / unless it is a bind mount
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// Optional fields like `shared:N` or `master:N`, describing the propagation
+    pub optional_fields: Vec<String>,
+}
+
+impl Mount {
+    /// Whether mounts and unmounts below this mount propagate to its peers
+    pub fn is_shared(&self) -> bool {
+        self.optional_fields
+            .iter()
+            .any(|field| field.starts_with("shared:"))
+    }
+}
+
+/// Read the mounts of the current mount namespace
+pub fn read() -> Result<Vec<Mount>, io::Error> {
+    // Mount points are arbitrary bytes, so this can't be read as a string
+    Ok(parse(&fs::read("/proc/self/mountinfo")?))
+}
+
+/// Parse the contents of a mountinfo file, see proc(5)
+pub fn parse(contents: &[u8]) -> Vec<Mount> {
+    contents
+        .split(|&b| b == b'\n')
+        .filter_map(|line| {
+            let mut fields = line.split(|&b| b == b' ');
+            let (major, minor) = std::str::from_utf8(fields.nth(2)?).ok()?.split_once(':')?;
+            let device = (major.parse().ok()?, minor.parse().ok()?);
+            let root = fields.next()?;
+            let mount_point = fields.next()?;
+            // The optional fields come after the mount options, up to a single -
+            let optional_fields = fields
+                .skip(1)
+                .take_while(|&field| field != b"-")
+                .map(|field| String::from_utf8_lossy(field).into_owned())
+                .collect();
+            Some(Mount {
+                device,
+                root: unescape(root),
+                mount_point: unescape(mount_point),
+                optional_fields,
+            })
+        })
+        .collect()
+}
+
+/// Undo the octal escapes of whitespace and backslashes in mountinfo paths
+fn unescape(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match escaped {
+            Some(c) => {
+                out.push(c);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(out))
+}
+
+/// The mounts at or below path
+pub fn below<'a>(mounts: &'a [Mount], path: &'a Path) -> impl Iterator<Item = &'a Mount> {
+    mounts
+        .iter()
+        .filter(move |m| m.mount_point.starts_with(path))
+}
@@ -0,0 +1,33 @@
+use std::mem;
+
+use nix::{
+    errno::Errno,
+    libc,
+    sys::socket::{socket, AddressFamily, SockFlag, SockType},
+    unistd::close,
+};
+
+/// Bring a network interface up, like `ip link set NAME up`
+pub fn set_up(name: &str) -> Result<(), Errno> {
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    if name.len() >= request.ifr_name.len() {
+        return Err(Errno::EINVAL);
+    }
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let result = Errno::result(unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut request) })
+        .and_then(|_| {
+            unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+            Errno::result(unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &request) })
+        });
+    close(fd)?;
+    result.map(drop)
+}
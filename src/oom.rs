@@ -0,0 +1,66 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+/// The number of processes the OOM killer killed in the memory cgroup AppRun was started in
+#[derive(Debug)]
+pub struct OomKills {
+    file: File,
+}
+
+impl OomKills {
+    /// Open the counter of the current memory cgroup: `memory.oom_control` of cgroup v1's
+    /// memory controller if it is in use, and `memory.events` of cgroup v2 otherwise.
+    /// This has to happen before creating a cgroup namespace, which changes the paths.
+    pub fn open() -> io::Result<OomKills> {
+        let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+        let mut v2 = None;
+        let mut v1 = None;
+        for line in cgroups.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (Some(_), Some(controllers), Some(path)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let path = path.trim_start_matches('/');
+            if controllers.is_empty() {
+                v2 = Some(
+                    PathBuf::from("/sys/fs/cgroup")
+                        .join(path)
+                        .join("memory.events"),
+                );
+            } else if controllers
+                .split(',')
+                .any(|controller| controller == "memory")
+            {
+                v1 = Some(
+                    PathBuf::from("/sys/fs/cgroup/memory")
+                        .join(path)
+                        .join("memory.oom_control"),
+                );
+            }
+        }
+        let path = v1
+            .or(v2)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in a memory cgroup"))?;
+        Ok(OomKills {
+            file: File::open(path)?,
+        })
+    }
+
+    /// The number of OOM kills so far
+    pub fn count(&self) -> io::Result<u64> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|count| count.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no oom_kill count"))
+    }
+}
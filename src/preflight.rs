@@ -0,0 +1,52 @@
+use std::path::{Path, PathBuf};
+
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    sys::stat::Mode,
+    unistd::close,
+};
+
+/// Paths checked by --preflight unless others are given
+pub const DEFAULT_PATHS: [&str; 6] = [
+    "/dev/null",
+    "/dev/dri",
+    "/dev/snd",
+    "/dev/fuse",
+    "/dev/kvm",
+    "/dev/megaraid_sas_ioctl_node",
+];
+
+/// Whether a path could be opened on the host and inside the container
+#[derive(Debug)]
+pub struct Openable {
+    pub path: PathBuf,
+    pub on_host: Result<(), Errno>,
+    pub in_container: Result<(), Errno>,
+}
+
+impl Openable {
+    /// Whether the path works on the host, but not inside the container
+    pub fn is_lost(&self) -> bool {
+        self.on_host.is_ok() && self.in_container.is_err()
+    }
+}
+
+/// Test if a file is openable, without blocking on FIFOs or terminals
+pub fn test_openable(path: &Path) -> Result<(), Errno> {
+    let flags = OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC | OFlag::O_NOCTTY;
+    let fd = crate::retry_on_eintr(|| open(path, flags, Mode::empty()))?;
+    close(fd)
+}
+
+/// Try to open each of paths on the host and below mount_dir, where the container root is
+pub fn check(mount_dir: &Path, paths: &[PathBuf]) -> Vec<Openable> {
+    paths
+        .iter()
+        .map(|path| Openable {
+            path: path.clone(),
+            on_host: test_openable(path),
+            in_container: test_openable(&mount_dir.join(path.strip_prefix("/").unwrap_or(path))),
+        })
+        .collect()
+}
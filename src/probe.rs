@@ -0,0 +1,56 @@
+use std::{
+    cell::RefCell,
+    io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+type Request = (PathBuf, Sender<io::Result<bool>>);
+
+/// Checks whether paths exist on a long-lived worker thread, so that a check stuck on
+/// a broken NFS mount or automount can be given up on after a timeout.
+///
+/// The worker is started by the first check. A worker that timed out is left behind,
+/// since the blocking call can't be cancelled, and the next check starts a new one.
+#[derive(Debug, Default)]
+pub struct Prober {
+    worker: RefCell<Option<Sender<Request>>>,
+}
+
+impl Prober {
+    /// Check whether path exists, giving up after timeout
+    pub fn exists(
+        &self,
+        path: &Path,
+        timeout: Duration,
+    ) -> Result<io::Result<bool>, RecvTimeoutError> {
+        let mut worker = self.worker.borrow_mut();
+        let (reply, receiver) = mpsc::channel();
+        let request = (path.to_path_buf(), reply);
+        if let Err(mpsc::SendError(request)) = worker.get_or_insert_with(spawn_worker).send(request)
+        {
+            // The worker is gone, which only happens if it panicked
+            *worker = Some(spawn_worker());
+            let _ = worker.as_ref().unwrap().send(request);
+        }
+
+        // recv_timeout already retries internally when interrupted by a signal
+        let result = receiver.recv_timeout(timeout);
+        if result.is_err() {
+            *worker = None;
+        }
+        result
+    }
+}
+
+fn spawn_worker() -> Sender<Request> {
+    let (sender, requests) = mpsc::channel::<Request>();
+    thread::spawn(move || {
+        for (path, reply) in requests {
+            let _ = reply.send(path.try_exists());
+        }
+    });
+    sender
+}
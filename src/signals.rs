@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use nix::{
+    libc,
+    sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd::Pid,
+};
+
+/// The signals always relayed to the entrypoint
+const FORWARDED: [Signal; 4] = [
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+];
+
+/// The PID signals are forwarded to, set once the entrypoint is forked
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// Seconds the entrypoint has to exit after a forwarded signal before it is killed,
+/// if it is PID 1 of its namespace, like `docker stop` gives a container
+const KILL_GRACE_SECONDS: u32 = 10;
+
+/// Whether the entrypoint is killed once the grace period after a forwarded signal is over
+static KILL_AFTER_GRACE: AtomicBool = AtomicBool::new(false);
+
+fn forwarded(extra: &[Signal]) -> SigSet {
+    let mut set = SigSet::empty();
+    for &signal in FORWARDED.iter().chain(extra) {
+        set.add(signal);
+    }
+    set
+}
+
+/// Block the forwarded signals, including the extra ones, so that none arrive between
+/// forking the entrypoint and setting up the forwarding
+pub fn block(extra: &[Signal]) -> nix::Result<()> {
+    forwarded(extra).thread_block()
+}
+
+/// Unblock the forwarded signals again, in the child
+pub fn unblock(extra: &[Signal]) -> nix::Result<()> {
+    forwarded(extra).thread_unblock()
+}
+
+extern "C" fn forward(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // As PID 1, the entrypoint ignores every signal it has no handler for, even from the
+    // terminal, so it might never exit. Only the first signal starts the grace period.
+    let terminating = FORWARDED
+        .iter()
+        .any(|&forwarded| forwarded as libc::c_int == signal);
+    if terminating && KILL_AFTER_GRACE.swap(false, Ordering::Relaxed) {
+        unsafe { libc::alarm(KILL_GRACE_SECONDS) };
+    }
+    // Signals from the terminal, like Ctrl-C, already reach the whole foreground process group
+    if unsafe { (*info).si_code } == libc::SI_KERNEL {
+        return;
+    }
+    let child = CHILD.load(Ordering::Relaxed);
+    if child > 0 {
+        unsafe { libc::kill(child, signal) };
+    }
+}
+
+extern "C" fn kill_child(_: libc::c_int) {
+    let child = CHILD.load(Ordering::Relaxed);
+    if child > 0 {
+        unsafe { libc::kill(child, libc::SIGKILL) };
+    }
+}
+
+/// Relay SIGTERM, SIGINT, SIGHUP and SIGQUIT, and the extra signals, to child from now on,
+/// instead of being killed by them, so that AppRun waits for the child to exit.
+/// Unblocks the signals from [`block`]. If child is PID 1 of a PID namespace, it is killed
+/// [`KILL_GRACE_SECONDS`] after the first of the four, since it ignores those it has no
+/// handler for.
+pub fn forward_to(child: Pid, is_init: bool, extra: &[Signal]) -> nix::Result<()> {
+    CHILD.store(child.as_raw(), Ordering::Relaxed);
+    if is_init {
+        let action = SigAction::new(
+            SigHandler::Handler(kill_child),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGALRM, &action) }?;
+        KILL_AFTER_GRACE.store(true, Ordering::Relaxed);
+    }
+    let action = SigAction::new(
+        SigHandler::SigAction(forward),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for &signal in FORWARDED.iter().chain(extra) {
+        unsafe { sigaction(signal, &action) }?;
+    }
+    unblock(extra)
+}
+
+/// Stop relaying signals once the child has been reaped, since its PID may be reused
+pub fn child_exited() {
+    CHILD.store(0, Ordering::Relaxed);
+    unsafe { libc::alarm(0) };
+}
@@ -0,0 +1,66 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Stop listing after this many entries, in case a full host root is bound
+const MAX_ENTRIES: usize = 100_000;
+
+/// Write a listing of the tree under root to out, one path per line as seen from inside
+/// the container. Directories end with `/` and symlinks are shown with their target.
+/// Symlinks are not followed, and directories deeper than max_depth are not entered.
+pub fn write_listing(root: &Path, out: &Path, max_depth: usize) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(out)?);
+    writeln!(writer, "/")?;
+    let mut count = 0;
+    list_dir(&mut writer, root, Path::new("/"), max_depth, &mut count)?;
+    if count >= MAX_ENTRIES {
+        writeln!(writer, "# truncated after {MAX_ENTRIES} entries")?;
+    }
+    writer.flush()
+}
+
+fn list_dir(
+    writer: &mut impl Write,
+    dir: &Path,
+    container_dir: &Path,
+    depth: usize,
+    count: &mut usize,
+) -> io::Result<()> {
+    if depth == 0 {
+        return Ok(());
+    }
+    // Unreadable directories are listed, just not entered
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        if *count >= MAX_ENTRIES {
+            return Ok(());
+        }
+        *count += 1;
+
+        let path = entry.path();
+        let container_path = container_dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let target = fs::read_link(&path)?;
+            writeln!(
+                writer,
+                "{} -> {}",
+                container_path.display(),
+                target.display()
+            )?;
+        } else if file_type.is_dir() {
+            writeln!(writer, "{}/", container_path.display())?;
+            list_dir(writer, &path, &container_path, depth - 1, count)?;
+        } else {
+            writeln!(writer, "{}", container_path.display())?;
+        }
+    }
+    Ok(())
+}
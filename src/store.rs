@@ -0,0 +1,67 @@
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const STORE_DIR: &[u8] = b"/nix/store/";
+const HASH_LEN: usize = 32;
+const HASH_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+fn is_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"+-._?=".contains(&c)
+}
+
+/// Find all store paths referenced in some bytes, as `<hash>-<name>`
+pub fn references(bytes: &[u8]) -> BTreeSet<String> {
+    let mut refs = BTreeSet::new();
+    let mut rest = bytes;
+    while let Some(pos) = rest.windows(STORE_DIR.len()).position(|w| w == STORE_DIR) {
+        rest = &rest[pos + STORE_DIR.len()..];
+        let hash_ok = rest.len() > HASH_LEN
+            && rest[..HASH_LEN].iter().all(|c| HASH_CHARS.contains(c))
+            && rest[HASH_LEN] == b'-';
+        if !hash_ok {
+            continue;
+        }
+        let len = rest.iter().take_while(|&&c| is_name_char(c)).count();
+        if len > HASH_LEN + 1 {
+            refs.insert(String::from_utf8_lossy(&rest[..len]).into_owned());
+        }
+        rest = &rest[len..];
+    }
+    refs
+}
+
+/// Translate a path inside the container's /nix into a path under nix_dir on the host
+pub fn host_path(nix_dir: &Path, path: &Path) -> Option<PathBuf> {
+    path.strip_prefix("/nix").ok().map(|rel| nix_dir.join(rel))
+}
+
+/// Follow a chain of at most max_depth symlinks, resolving links into /nix against nix_dir.
+/// Returns the final path on the host.
+pub fn resolve(nix_dir: &Path, path: &Path, max_depth: usize) -> Result<PathBuf, io::Error> {
+    let mut path = path.to_path_buf();
+    for _ in 0..=max_depth {
+        let on_host = host_path(nix_dir, &path).unwrap_or_else(|| path.clone());
+        if !fs::symlink_metadata(&on_host)?.is_symlink() {
+            return Ok(on_host);
+        }
+        let target = fs::read_link(&on_host)?;
+        path = match path.parent() {
+            Some(parent) if target.is_relative() => parent.join(target),
+            _ => target,
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("too many symlink levels (more than {max_depth}) while resolving {path:?}"),
+    ))
+}
+
+/// The `<hash>-<name>` store path that a path under store_dir belongs to
+pub fn store_path_of(store_dir: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(store_dir).ok()?;
+    let name = relative.components().next()?.as_os_str().to_str()?;
+    Some(name.to_string())
+}
@@ -0,0 +1,3000 @@
+//! Integration tests that launch the AppRun binary with a synthetic bundle.
+//!
+//! They need to be run as root or with unprivileged user namespaces enabled,
+//! and are skipped with a message otherwise.
+
+use std::{
+    ffi::{CString, OsStr},
+    fs,
+    io::{BufRead, BufReader},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, MetadataExt, PermissionsExt},
+        io::{AsRawFd, FromRawFd},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use nix::{
+    fcntl::{open, OFlag},
+    mount::{mount, MsFlags},
+    pty::openpty,
+    sched::{unshare, CloneFlags},
+    sys::{
+        signal::{kill, Signal},
+        stat::{makedev, mknod, Mode, SFlag},
+    },
+    unistd::{close, dup2, getsid, write, Gid, Pid, Uid, User},
+};
+use tempfile::TempDir;
+
+/// Check if we can create the namespaces AppRun needs
+fn namespaces_available() -> bool {
+    let flags = if Uid::effective().is_root() {
+        CloneFlags::CLONE_NEWNS
+    } else {
+        CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS
+    };
+    let mut cmd = Command::new("true");
+    unsafe {
+        cmd.pre_exec(move || unshare(flags).map_err(Into::into));
+    }
+    cmd.status().map(|s| s.success()).unwrap_or(false)
+}
+
+/// A shell from the host to use as entrypoint.
+/// It must live outside /nix, which is replaced by the bundled store.
+fn host_shell() -> Option<PathBuf> {
+    let shell = fs::canonicalize("/bin/sh").ok()?;
+    (!shell.starts_with("/nix")).then_some(shell)
+}
+
+macro_rules! require_namespaces {
+    () => {
+        if !namespaces_available() {
+            eprintln!("skipping: cannot create user and mount namespaces");
+            return;
+        }
+        if host_shell().is_none() {
+            eprintln!("skipping: no /bin/sh outside of /nix");
+            return;
+        }
+    };
+}
+
+/// A minimal bundle: a nix directory with an empty store and a mount point
+struct Bundle {
+    dir: TempDir,
+}
+
+impl Bundle {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("nix/store")).unwrap();
+        fs::create_dir(dir.path().join("mountroot")).unwrap();
+        Bundle { dir }
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Create a directory in the bundled store, returning its path inside the container
+    fn add_store_path(&self, name: &str) -> PathBuf {
+        let store_path = format!("0123456789abcdfghijklmnpqrsvwxyz-{name}");
+        fs::create_dir_all(self.path().join("nix/store").join(&store_path)).unwrap();
+        Path::new("/nix/store").join(store_path)
+    }
+
+    /// Path of a container path inside the bundled nix directory
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.path().join(path.strip_prefix("/").unwrap())
+    }
+
+    /// A command running AppRun on this bundle with a shell as entrypoint
+    fn command(&self) -> Command {
+        self.command_with_entrypoint(&host_shell().unwrap())
+    }
+
+    /// A command running AppRun on this bundle
+    fn command_with_entrypoint(&self, entrypoint: &Path) -> Command {
+        let mut cmd = self.command_without_entrypoint();
+        cmd.arg(format!("--apprun-entrypoint={}", entrypoint.display()));
+        cmd
+    }
+
+    /// A command running AppRun on this bundle, leaving the entrypoint to the caller
+    fn command_without_entrypoint(&self) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_app-run"));
+        cmd.current_dir("/")
+            .arg(format!(
+                "--apprun-nix-dir={}",
+                self.path().join("nix").display()
+            ))
+            .arg(format!(
+                "--apprun-mount-dir={}",
+                self.path().join("mountroot").display()
+            ));
+        cmd
+    }
+
+    /// Run a shell script in the container
+    fn run_sh(&self, apprun_args: &[&str], script: &str) -> Output {
+        self.command()
+            .args(apprun_args)
+            .arg("-c")
+            .arg(script)
+            .output()
+            .unwrap()
+    }
+}
+
+/// Run a command in a new mount namespace, cut off from the host,
+/// with the given propagation for all mounts. This needs root.
+fn with_root_propagation(cmd: &mut Command, propagation: MsFlags) {
+    unsafe {
+        cmd.pre_exec(move || {
+            unshare(CloneFlags::CLONE_NEWNS)?;
+            for flag in [MsFlags::MS_PRIVATE, propagation] {
+                mount(
+                    None::<&str>,
+                    "/",
+                    None::<&str>,
+                    flag | MsFlags::MS_REC,
+                    None::<&str>,
+                )?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Run a command in a new mount namespace with a FUSE mount at `dir` that never answers,
+/// so that looking up anything below it hangs like on a stale network mount. This needs root.
+fn with_hung_mount(cmd: &mut Command, dir: &Path) {
+    // A fixed descriptor, so that nothing has to be allocated after the fork
+    const FUSE_FD: i32 = 100;
+    let fuse = fs::File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .unwrap();
+    let data = format!("fd={FUSE_FD},rootmode=40000,user_id=0,group_id=0");
+    let dir = dir.to_path_buf();
+    unsafe {
+        cmd.pre_exec(move || {
+            unshare(CloneFlags::CLONE_NEWNS)?;
+            mount(
+                None::<&str>,
+                "/",
+                None::<&str>,
+                MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+                None::<&str>,
+            )?;
+            // Kept open without ever being read, and inherited by AppRun
+            dup2(fuse.as_raw_fd(), FUSE_FD)?;
+            mount(
+                Some("apprun-test"),
+                &dir,
+                Some("fuse"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(data.as_str()),
+            )?;
+            Ok(())
+        });
+    }
+}
+
+/// Binds needed to run the host shell, and nothing else
+fn minimal_binds() -> Vec<String> {
+    ["/bin", "/lib", "/lib32", "/lib64", "/usr"]
+        .iter()
+        .filter(|path| Path::new(path).exists())
+        .map(|path| format!("--apprun-bind={path}"))
+        .collect()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "AppRun failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn runs_entrypoint_with_args() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&[], r#"echo "$0" "$1""#);
+    assert_eq!(
+        stdout(&output),
+        format!("{} \n", env!("CARGO_BIN_EXE_app-run"))
+    );
+
+    let output = bundle
+        .command()
+        .args(["-c", r#"echo "$1""#, "sh", "hello world"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "hello world\n");
+}
+
+#[test]
+fn sees_bundled_nix() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("hello");
+    fs::write(bundle.host_path(&store_path).join("greeting"), "hi").unwrap();
+
+    let output = bundle.run_sh(
+        &[],
+        &format!("cat {}/greeting; ls /nix/store", store_path.display()),
+    );
+    assert_eq!(
+        stdout(&output),
+        format!("hi{}\n", store_path.file_name().unwrap().to_str().unwrap())
+    );
+}
+
+#[test]
+fn relative_dirs_are_resolved_from_working_directory() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("hello");
+    fs::write(bundle.host_path(&store_path).join("greeting"), "hi").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_app-run"))
+        .current_dir(bundle.path())
+        .args(["--apprun-nix-dir=nix", "--apprun-mount-dir=mountroot"])
+        .arg(format!(
+            "--apprun-entrypoint={}",
+            host_shell().unwrap().display()
+        ))
+        .arg("-c")
+        .arg(format!("cat {}/greeting", store_path.display()))
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "hi");
+}
+
+#[test]
+fn version_exits_without_a_bundle() {
+    for arg in ["--apprun-version", "--version"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_app-run"))
+            .arg(arg)
+            .current_dir("/")
+            .output()
+            .unwrap();
+        assert_eq!(
+            stdout(&output),
+            format!("nix-apprun v{}\n", env!("CARGO_PKG_VERSION"))
+        );
+    }
+}
+
+#[test]
+fn version_with_other_arguments_is_passed_to_app() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .args(["-c", "echo \"$0\"", "--version"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "--version\n");
+}
+
+#[test]
+fn mkdir_creates_and_removes_mount_dir() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let mount_dir = bundle.path().join("missing/mountroot");
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_app-run"))
+            .current_dir("/")
+            .arg(format!(
+                "--apprun-nix-dir={}",
+                bundle.path().join("nix").display()
+            ))
+            .arg(format!("--apprun-mount-dir={}", mount_dir.display()))
+            .arg(format!(
+                "--apprun-entrypoint={}",
+                host_shell().unwrap().display()
+            ))
+            .args(args)
+            .args(["-c", "echo hi"])
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("mount directory does not exist"));
+
+    assert_eq!(stdout(&run(&["--apprun-mkdir"])), "hi\n");
+    assert!(!bundle.path().join("missing").exists());
+}
+
+#[test]
+fn only_sees_bound_paths() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let binds = minimal_binds();
+    let binds: Vec<&str> = binds.iter().map(String::as_str).collect();
+
+    let output = bundle.run_sh(&binds, "ls /");
+    let mut entries: Vec<String> = stdout(&output).lines().map(String::from).collect();
+    entries.sort();
+
+    let mut expected: Vec<String> = binds
+        .iter()
+        .map(|bind| bind.trim_start_matches("--apprun-bind=/").to_string())
+        .chain(["nix".to_string()])
+        .collect();
+    expected.sort();
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn symlink_loop_in_entrypoint_is_an_error() {
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("loop");
+    symlink(
+        store_path.join("b"),
+        bundle.host_path(&store_path).join("a"),
+    )
+    .unwrap();
+    symlink(
+        store_path.join("a"),
+        bundle.host_path(&store_path).join("b"),
+    )
+    .unwrap();
+
+    let output = bundle
+        .command_with_entrypoint(&store_path.join("a"))
+        .args(["--apprun-probe-store", "--apprun-max-symlink-depth=10"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("too many symlink levels (more than 10)"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn probe_store_reports_missing_store_paths() {
+    let bundle = Bundle::new();
+    let present = bundle.add_store_path("present");
+    let store_path = bundle.add_store_path("app");
+    let entrypoint = store_path.join("app");
+    fs::write(
+        bundle.host_path(&entrypoint),
+        format!(
+            "#!/bin/sh\n{}/bin/tool\n/nix/store/0123456789abcdfghijklmnpqrsvwxyz-missing/lib/libmissing.so\n",
+            present.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(
+        bundle.host_path(&entrypoint),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+
+    let output = bundle
+        .command_with_entrypoint(&entrypoint)
+        .arg("--apprun-probe-store")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Missing store path /nix/store/0123456789abcdfghijklmnpqrsvwxyz-missing"),
+        "{stderr}"
+    );
+    assert!(
+        !stderr.contains(&format!("Missing store path {}", present.display())),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("1 store paths referenced by the entrypoint are missing"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn entrypoint_is_checked_before_setup() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("app");
+    let host_dir = bundle.host_path(&store_path);
+    fs::create_dir(host_dir.join("bin")).unwrap();
+    fs::copy(host_shell().unwrap(), host_dir.join("bin/sh")).unwrap();
+    let write_script = |name: &str, contents: String, mode: u32| {
+        let path = host_dir.join(name);
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        symlink(store_path.join(name), bundle.path().join(name)).unwrap();
+        bundle.path().join(name)
+    };
+    let run = |entrypoint: &Path| bundle.command_with_entrypoint(entrypoint).output().unwrap();
+
+    let script = write_script(
+        "script",
+        format!("#!{}/bin/sh\necho script\n", store_path.display()),
+        0o755,
+    );
+    assert_eq!(stdout(&run(&script)), "script\n");
+
+    let missing_interpreter = write_script(
+        "missing-interpreter",
+        format!("#! {}/bin/bash -e\n", store_path.display()),
+        0o755,
+    );
+    let not_executable = write_script("not-executable", "#!/bin/sh\n".to_string(), 0o644);
+    let dangling = bundle.path().join("dangling");
+    symlink(store_path.join("missing"), &dangling).unwrap();
+    for (entrypoint, message) in [
+        (missing_interpreter, "The interpreter"),
+        (not_executable, "which is not an executable file"),
+        (dangling, "does not resolve to an existing file"),
+    ] {
+        let output = run(&entrypoint);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "{stderr}");
+    }
+}
+
+#[test]
+fn root_symlinks_are_recreated() {
+    require_namespaces!();
+    let Some((link, target)) = fs::read_dir("/")
+        .unwrap()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name() != "nix")
+        .find_map(|entry| Some((entry.path(), fs::read_link(entry.path()).ok()?)))
+    else {
+        eprintln!("skipping: no symlinks in the host's /");
+        return;
+    };
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&[], &format!("readlink {}", link.display()));
+    assert_eq!(stdout(&output), format!("{}\n", target.display()));
+}
+
+#[test]
+fn bind_if_depends_on_condition() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let source = bundle.path().join("data");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("marker"), "bound\n").unwrap();
+    let present = bundle.path().join("present");
+    fs::write(&present, "").unwrap();
+
+    let run = |condition: &Path| {
+        let mut args = minimal_binds();
+        args.push(format!(
+            "--apprun-bind-if={}:{}:/data",
+            condition.display(),
+            source.display()
+        ));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        bundle.run_sh(
+            &args,
+            "if test -e /data/marker; then cat /data/marker; else echo skipped; fi",
+        )
+    };
+
+    assert_eq!(stdout(&run(&present)), "bound\n");
+    assert_eq!(stdout(&run(&bundle.path().join("absent"))), "skipped\n");
+}
+
+#[test]
+fn limit_mounts_stops_with_clear_error() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let mut args = minimal_binds();
+    for i in 0..20 {
+        let dir = bundle.path().join(format!("dir{i}"));
+        fs::create_dir(&dir).unwrap();
+        args.push(format!("--apprun-bind={}:/dir{i}", dir.display()));
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = bundle.run_sh(&args, "echo ran");
+    assert_eq!(stdout(&output), "ran\n");
+
+    let mut limited = args.clone();
+    limited.push("--apprun-limit-mounts=10");
+    let output = bundle.run_sh(&limited, "echo ran");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("more than 10 bind mounts needed (10 mounted so far)"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn missing_binds_fail_unless_optional() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let mut args = minimal_binds();
+    args.push("--apprun-bind-try=/apprun-missing".into());
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = bundle.run_sh(
+        &args,
+        "test -d /usr && test ! -e /apprun-missing && echo ok",
+    );
+    assert_eq!(stdout(&output), "ok\n");
+    assert!(
+        !String::from_utf8_lossy(&output.stderr).contains("apprun-missing"),
+        "optional bind was not skipped quietly"
+    );
+
+    let output = bundle.run_sh(&["--apprun-bind=/apprun-missing"], "true");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn failed_binds_fail_unless_optional() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    // The mount namespace AppRun creates can't be bound into itself
+    let run = |bind: &str| {
+        let mut args = minimal_binds();
+        args.push(format!("{bind}=/proc/self/ns/mnt:/mntns"));
+        bundle
+            .command()
+            .args(args)
+            .args(["-c", "echo ran"])
+            .output()
+            .unwrap()
+    };
+
+    let output = run("--apprun-bind-try");
+    assert_eq!(stdout(&output), "ran\n");
+
+    let output = run("--apprun-bind");
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Failed to mount \"/proc/self/ns/mnt\""),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn quiet_skips_hides_skip_warnings() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: needs root to mount FUSE");
+        return;
+    }
+    let bundle = Bundle::new();
+    let hung = tempfile::tempdir().unwrap();
+    let mut args = minimal_binds();
+    args.push(format!("--apprun-bind-try={}/data", hung.path().display()));
+    args.push("--apprun-mount-timeout=0.2".into());
+    let run = |quiet: bool| {
+        let mut cmd = bundle.command();
+        with_hung_mount(&mut cmd, hung.path());
+        cmd.env_remove("RUST_LOG").args(&args);
+        if quiet {
+            cmd.arg("--apprun-quiet-skips");
+        }
+        cmd.args(["-c", "echo ran"]).output().unwrap()
+    };
+
+    let output = run(false);
+    assert_eq!(stdout(&output), "ran\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Timed out to check existance of \"data\""),
+        "{stderr}"
+    );
+
+    let output = run(true);
+    assert_eq!(stdout(&output), "ran\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("Timed out"), "{stderr}");
+}
+
+#[test]
+fn bind_timeout_gives_up_on_hung_paths() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: needs root to mount FUSE");
+        return;
+    }
+    let bundle = Bundle::new();
+    let hung = tempfile::tempdir().unwrap();
+    let data = hung.path().join("data");
+    let run = |bind: &str| {
+        let mut cmd = bundle.command();
+        with_hung_mount(&mut cmd, hung.path());
+        let started = std::time::Instant::now();
+        let output = cmd
+            .args(minimal_binds())
+            .arg(format!("{bind}={}", data.display()))
+            .arg(format!(
+                "--apprun-bind-timeout={}=0.2",
+                hung.path().display()
+            ))
+            .args(["-c", "test ! -e /data && echo ran"])
+            .output()
+            .unwrap();
+        // Well below the default mount timeout of 5 seconds
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+        output
+    };
+
+    let output = run("--apprun-bind-try");
+    assert_eq!(stdout(&output), "ran\n");
+
+    let output = run("--apprun-bind");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("given with --bind does not exist or could not be checked"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn bind_try_label_binds_mounted_devices() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: creating device nodes needs root");
+        return;
+    }
+    let bundle = Bundle::new();
+    // Devices named after the filesystem of the bundle, which is mounted, and an unused number
+    let disk = bundle.path().join("disk");
+    fs::create_dir_all(disk.join("by-label")).unwrap();
+    fs::create_dir_all(disk.join("by-uuid")).unwrap();
+    let dev = fs::metadata(bundle.path()).unwrap().dev();
+    for (name, rdev) in [("mounted", dev), ("unmounted", makedev(4095, 4095))] {
+        let node = disk.join(name);
+        mknod(&node, SFlag::S_IFBLK, Mode::S_IRUSR, rdev).unwrap();
+        symlink(&node, disk.join("by-label").join(name)).unwrap();
+    }
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .env("RUST_LOG", "debug")
+        .arg(format!("--apprun-disk-dir={}", disk.display()))
+        .args([
+            "--apprun-bind-try-label=LABEL=mounted:/media/mounted",
+            "--apprun-bind-try-label=LABEL=unmounted:/media/unmounted",
+            "--apprun-bind-try-label=UUID=absent:/media/absent",
+            "-c",
+            "stat -c %d /media/mounted; test ! -e /media/unmounted && test ! -e /media/absent",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), format!("{dev}\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("WARN"), "{stderr}");
+    assert!(
+        stderr.contains("Skipping device with label \"unmounted\""),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Skipping device with UUID \"absent\""),
+        "{stderr}"
+    );
+
+    let output = bundle.run_sh(&["--apprun-bind-try-label=PARTUUID=x:/media/x"], "true");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn excludes_top_level_paths() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(
+        &["--apprun-exclude=/etc", "--apprun-exclude=opt"],
+        "test ! -e /etc && test ! -e /opt && test -d /usr && echo ok",
+    );
+    assert_eq!(stdout(&output), "ok\n");
+}
+
+#[test]
+fn exclude_rejects_nested_paths() {
+    let bundle = Bundle::new();
+
+    for path in ["/usr/share", "media/usb", "/", "..", ""] {
+        let output = bundle.run_sh(&[&format!("--apprun-exclude={path}")], "true");
+        assert!(!output.status.success(), "{path:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("expected a top-level path"), "{stderr}");
+    }
+}
+
+#[test]
+fn tmpfs_size_limits_the_root() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&["--apprun-tmpfs-size=1M"], "df -k / | tail -n 1");
+    let fields: Vec<String> = stdout(&output)
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    assert_eq!(fields[1], "1024", "{fields:?}");
+
+    let output = bundle.run_sh(&["--apprun-tmpfs-size=1X"], "true");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn private_tmp_hides_host_tmp() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let marker = tempfile::Builder::new()
+        .prefix("apprun-test-")
+        .tempfile_in("/tmp")
+        .unwrap();
+
+    let output = bundle.run_sh(
+        &["--apprun-private-tmp", "--apprun-tmpfs-size=1M"],
+        &format!(
+            "test ! -e {} && touch /tmp/apprun-private && df -k /tmp | tail -n 1",
+            marker.path().display()
+        ),
+    );
+    let output = stdout(&output);
+    assert_eq!(output.split_whitespace().nth(1), Some("1024"), "{output}");
+    assert!(!Path::new("/tmp/apprun-private").exists());
+}
+
+#[test]
+fn pidfile_has_pid_of_entrypoint() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let pidfile = bundle.path().join("app.pid");
+
+    let mut child = bundle
+        .command()
+        .arg(format!("--apprun-pidfile={}", pidfile.display()))
+        // Keep running until stdin is closed
+        .args(["-c", "echo $$; read _ || true"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert_eq!(fs::read_to_string(&pidfile).unwrap(), line);
+    drop(child.stdin.take());
+    assert!(child.wait().unwrap().success());
+    assert!(!pidfile.exists());
+
+    // Replacing AppRun, the entrypoint keeps its PID, and the file is left behind
+    let child = bundle
+        .command()
+        .arg(format!("--apprun-pidfile={}", pidfile.display()))
+        .arg("--apprun-exec-replace")
+        .args(["-c", "echo $$"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    assert_eq!(
+        stdout(&child.wait_with_output().unwrap()),
+        format!("{pid}\n")
+    );
+    assert_eq!(fs::read_to_string(&pidfile).unwrap(), format!("{pid}\n"));
+}
+
+#[test]
+fn sys_and_proc_are_read_only() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    // Whether the topmost mounts at /proc and /sys are read-only or read-write
+    let script =
+        "awk '$5 == \"/proc\" || $5 == \"/sys\" { split($6, opts, \",\"); mode[$5] = opts[1] }
+        END { print mode[\"/proc\"], mode[\"/sys\"] }' /proc/self/mountinfo";
+
+    let output = bundle.run_sh(&[], script);
+    assert_eq!(stdout(&output), "ro ro\n");
+
+    let output = bundle.run_sh(&["--apprun-rw-sys"], script);
+    assert_eq!(stdout(&output), "rw rw\n");
+
+    // A fresh /proc is the app's own
+    let output = bundle.run_sh(&["--apprun-unshare-pid"], script);
+    assert_eq!(stdout(&output), "rw ro\n");
+}
+
+#[test]
+fn fake_passwd_describes_current_user() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let name = User::from_uid(Uid::current()).unwrap().unwrap().name;
+    let script = "id -un; id -gn; wc -l < /etc/passwd";
+
+    let output = bundle.run_sh(&["--apprun-fake-passwd"], script);
+    let output = stdout(&output);
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines[0], name);
+    // Only root, the user and nobody
+    assert!(lines[2].parse::<u32>().unwrap() <= 3, "{output}");
+
+    let output = bundle.run_sh(&["--apprun-fake-passwd", "--apprun-map-root"], script);
+    assert!(stdout(&output).starts_with("root\nroot\n"));
+}
+
+#[test]
+fn copied_files_are_snapshots() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let file = bundle.path().join("settings.conf");
+    fs::write(&file, "host\n").unwrap();
+    let script = format!("echo app >> {0}; cat {0}", file.display());
+
+    // Inside a bound directory, the copy is bound over the file
+    let copy = format!("--apprun-copy={}", file.display());
+    let output = bundle.run_sh(&[&copy], &script);
+    assert_eq!(stdout(&output), "host\napp\n");
+    assert_eq!(fs::read_to_string(&file).unwrap(), "host\n");
+
+    // Otherwise it is written into the container's root
+    let mut args = minimal_binds();
+    args.push(copy);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = bundle.run_sh(&args, &script);
+    assert_eq!(stdout(&output), "host\napp\n");
+    assert_eq!(fs::read_to_string(&file).unwrap(), "host\n");
+}
+
+#[test]
+fn container_root_is_unbindable() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&[], "cat /proc/self/mountinfo");
+    let mountinfo = stdout(&output);
+    // The mount point and the optional fields, like shared:N or unbindable
+    let mounts: Vec<(&str, &str)> = mountinfo
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(" - ").next().unwrap().split(' ').collect();
+            (fields[4], fields[6..].last().copied().unwrap_or(""))
+        })
+        .collect();
+    assert!(mounts.contains(&("/", "unbindable")), "{mountinfo}");
+    // Binding a parent of the mount directory, like /tmp, doesn't copy the container into it
+    let mount_dir = bundle.path().join("mountroot");
+    assert!(
+        !mounts
+            .iter()
+            .any(|(mount_point, _)| Path::new(mount_point).starts_with(&mount_dir)),
+        "{mountinfo}"
+    );
+    // The binds themselves can still be bound by the app
+    assert!(
+        mounts
+            .iter()
+            .all(|&(mount_point, fields)| mount_point == "/" || fields != "unbindable"),
+        "{mountinfo}"
+    );
+}
+
+#[test]
+fn nosuid_and_nodev_harden_host_binds() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    // Whether each mount has the nosuid and nodev options
+    let script = "awk '$5 == \"/usr\" || $5 == \"/dev\" || $5 == \"/nix\" {
+        print $5, ($6 ~ /nosuid/), ($6 ~ /nodev/) }' /proc/self/mountinfo | sort";
+
+    let output = bundle.run_sh(&["--apprun-nosuid", "--apprun-nodev"], script);
+    assert_eq!(stdout(&output), "/dev 1 0\n/nix 0 0\n/usr 1 1\n");
+}
+
+#[test]
+fn print_config_resolves_without_running() {
+    let bundle = Bundle::new();
+    // Never checked, since nothing is run
+    let output = bundle
+        .command_with_entrypoint(Path::new("/apprun-missing"))
+        .args([
+            "--apprun-print-config",
+            "--apprun-bind=/usr",
+            "--apprun-bind-try=/opt:/srv",
+            "--apprun-unshare-net",
+        ])
+        .output()
+        .unwrap();
+    let output = stdout(&output);
+
+    let expected = format!(
+        r#"{{"nix_dir":"{0}/nix","mount_dir":"{0}/mountroot","entrypoint":"/apprun-missing","#,
+        bundle.path().display()
+    );
+    assert!(output.starts_with(&expected), "{output}");
+    assert!(output.contains(
+        r#""binds":[{"source":"/usr","dest":"/usr","required":true},{"source":"/opt","dest":"/srv","required":false}]"#
+    ), "{output}");
+    assert!(output.contains(r#""net"]}"#), "{output}");
+    assert!(fs::read_dir(bundle.path().join("mountroot"))
+        .unwrap()
+        .next()
+        .is_none());
+}
+
+#[test]
+fn desktop_binds_runtime_dir_and_passes_on_display() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let runtime_dir = tempfile::tempdir().unwrap();
+    fs::write(runtime_dir.path().join("wayland-0"), "").unwrap();
+    let mut args = minimal_binds();
+    args.extend(["--apprun-desktop".into(), "--apprun-clearenv".into()]);
+
+    let output = bundle
+        .command()
+        .args(args)
+        .env("XDG_RUNTIME_DIR", runtime_dir.path())
+        .env("WAYLAND_DISPLAY", "wayland-0")
+        .args(["-c", "ls \"$XDG_RUNTIME_DIR\"; echo \"$WAYLAND_DISPLAY\""])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "wayland-0\nwayland-0\n");
+}
+
+#[test]
+fn drop_caps_keeps_only_added_capabilities() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let script = "awk '/^Cap(Eff|Bnd)/ { print $1, $2 }' /proc/self/status";
+
+    let output = bundle.run_sh(&["--apprun-map-root", "--apprun-drop-caps"], script);
+    assert_eq!(
+        stdout(&output),
+        "CapEff: 0000000000000000\nCapBnd: 0000000000000000\n"
+    );
+
+    let output = bundle.run_sh(
+        &[
+            "--apprun-map-root",
+            "--apprun-drop-caps",
+            "--apprun-cap-add=net_raw",
+        ],
+        script,
+    );
+    assert_eq!(
+        stdout(&output),
+        "CapEff: 0000000000002000\nCapBnd: 0000000000002000\n"
+    );
+}
+
+#[test]
+fn allow_setgroups_lets_the_app_change_groups() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: needs root to stand in for the setuid newuidmap and newgidmap");
+        return;
+    }
+    let host_path = std::env::var("PATH").unwrap();
+    let Some(setpriv) = std::env::split_paths(&host_path)
+        .map(|dir| dir.join("setpriv"))
+        .find(|path| path.is_file())
+    else {
+        eprintln!("skipping: no setpriv");
+        return;
+    };
+    let bundle = Bundle::new();
+    // AppRun runs as nobody, which has to reach the bundle and the binary
+    fs::set_permissions(bundle.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    let apprun = bundle.path().join("AppRun");
+    fs::copy(env!("CARGO_BIN_EXE_app-run"), &apprun).unwrap();
+
+    // Helpers that write the maps with the capabilities nobody is given below,
+    // like the real ones do by being setuid root
+    let helpers = bundle.path().join("helpers");
+    fs::create_dir(&helpers).unwrap();
+    for name in ["newuidmap", "newgidmap"] {
+        let path = helpers.join(name);
+        fs::write(
+            &path,
+            format!(
+                "#!{}\npid=$1\nshift\nfile={}\nmap=$(printf '%s %s %s\\n' \"$@\")\n\
+                 echo \"$map\" > /proc/$pid/$file\n",
+                host_shell().unwrap().display(),
+                if name == "newuidmap" {
+                    "uid_map"
+                } else {
+                    "gid_map"
+                },
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let with_helpers = format!("{}:{host_path}", helpers.display());
+
+    let run = |path: &str, args: &[&str]| {
+        Command::new(&setpriv)
+            .args([
+                "--reuid=65534",
+                "--regid=65534",
+                "--clear-groups",
+                "--inh-caps=+setuid,+setgid",
+                "--ambient-caps=+setuid,+setgid",
+            ])
+            .arg(&apprun)
+            .args(bundle.command().get_args())
+            .arg("--apprun-map-root")
+            .args(args)
+            .args(["-c", "setpriv --clear-groups true && echo changed"])
+            .env("PATH", path)
+            .current_dir("/")
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&with_helpers, &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("setgroups failed"));
+
+    let output = run(&with_helpers, &["--apprun-allow-setgroups"]);
+    assert_eq!(stdout(&output), "changed\n");
+
+    // Nothing in the bundle directory
+    let output = run(
+        bundle.path().to_str().unwrap(),
+        &["--apprun-allow-setgroups"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs newuidmap and newgidmap"));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn failed_unshare_stops_before_mounting() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let uid_map = format!("65534 {} 1", Uid::current());
+    let gid_map = format!("65534 {} 1", Gid::current());
+
+    let mut cmd = bundle.command();
+    cmd.args(["-c", "echo ran"]);
+    unsafe {
+        // Run AppRun as a user in a user namespace that can't have any nested ones
+        cmd.pre_exec(move || {
+            unshare(CloneFlags::CLONE_NEWUSER)?;
+            for (path, contents) in [
+                ("/proc/self/setgroups", "deny"),
+                ("/proc/self/uid_map", &uid_map),
+                ("/proc/self/gid_map", &gid_map),
+                ("/proc/sys/user/max_user_namespaces", "0"),
+            ] {
+                let fd = open(path, OFlag::O_WRONLY, Mode::empty())?;
+                write(fd, contents.as_bytes())?;
+                close(fd)?;
+            }
+            Ok(())
+        });
+    }
+    let output = cmd.output().unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("user.max_user_namespaces"), "{stderr}");
+}
+
+#[test]
+fn ns_fd_path_links_namespaces_while_app_runs() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let ns_dir = bundle.path().join("ns");
+
+    let mut child = bundle
+        .command()
+        .args(minimal_binds())
+        .arg(format!("--apprun-ns-fd-path={}", ns_dir.display()))
+        .arg("--apprun-unshare=pid,uts")
+        // Keep running until stdin is closed
+        .args(["-c", "readlink /proc/self/ns/uts; read _ || true"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let uts = fs::read_link(fs::read_link(ns_dir.join("uts")).unwrap()).unwrap();
+    assert_eq!(uts.to_str().unwrap(), line.trim_end());
+    assert!(ns_dir.join("mnt").exists());
+    assert!(ns_dir.join("pid").exists());
+    assert!(!ns_dir.join("net").exists());
+
+    drop(child.stdin.take());
+    assert!(child.wait().unwrap().success());
+    assert!(!ns_dir.exists());
+}
+
+#[test]
+fn preflight_warns_about_paths_lost_in_container() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let host_only = bundle.path().join("host-only");
+    fs::write(&host_only, "").unwrap();
+
+    let mut args = minimal_binds();
+    args.extend([
+        "--apprun-minimal-dev".to_string(),
+        "--apprun-preflight".to_string(),
+        "--apprun-preflight-path=/dev/null".to_string(),
+        format!("--apprun-preflight-path={}", host_only.display()),
+        "--apprun-preflight-path=/apprun-missing".to_string(),
+    ]);
+    let output = bundle
+        .command()
+        .args(args)
+        .args(["-c", "true"])
+        .output()
+        .unwrap();
+    stdout(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let warned = |path: &str| stderr.contains(&format!("{path:?} won't be accessible"));
+    assert!(warned(host_only.to_str().unwrap()), "{stderr}");
+    assert!(!warned("/dev/null"), "{stderr}");
+    assert!(!warned("/apprun-missing"), "{stderr}");
+}
+
+#[test]
+fn minimal_dev_has_only_common_nodes() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(
+        &["--apprun-minimal-dev"],
+        "echo discarded > /dev/null && test -c /dev/urandom && test -d /dev/shm \
+         && test \"$(readlink /dev/fd)\" = /proc/self/fd && test ! -e /dev/mem && echo ok",
+    );
+    assert_eq!(stdout(&output), "ok\n");
+}
+
+#[test]
+fn sets_hostname_in_uts_namespace() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let host = nix::unistd::gethostname().unwrap();
+
+    let output = bundle.run_sh(&["--apprun-unshare-uts"], "uname -n");
+    assert_eq!(stdout(&output), "appimage\n");
+    let output = bundle.run_sh(&["--apprun-hostname=sandbox"], "uname -n");
+    assert_eq!(stdout(&output), "sandbox\n");
+    assert_eq!(nix::unistd::gethostname().unwrap(), host);
+}
+
+#[test]
+fn hostname_from_bundle_is_stable_unless_given() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("hello-2.12.1");
+    let shell = store_path.join("sh");
+    fs::copy(host_shell().unwrap(), bundle.host_path(&shell)).unwrap();
+    let run = |args: &[&str]| {
+        let output = bundle
+            .command_with_entrypoint(&shell)
+            .args(args)
+            .args(["-c", "uname -n"])
+            .output()
+            .unwrap();
+        stdout(&output)
+    };
+
+    let hostname = run(&["--apprun-hostname-from-bundle"]);
+    assert_eq!(hostname, "hello-2-12-1-01234567\n");
+    assert_eq!(run(&["--apprun-hostname-from-bundle"]), hostname);
+    assert_eq!(
+        run(&["--apprun-hostname-from-bundle", "--apprun-hostname=sandbox"]),
+        "sandbox\n"
+    );
+}
+
+#[test]
+fn bundled_store_is_mounted_at_nix() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("hello");
+
+    let output = bundle.run_sh(&[], "ls /nix/store");
+    assert_eq!(
+        stdout(&output),
+        format!("{}\n", store_path.file_name().unwrap().to_str().unwrap())
+    );
+}
+
+#[test]
+fn missing_bundled_store_fails_unless_merging_host_store() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    fs::remove_dir(bundle.path().join("nix/store")).unwrap();
+
+    let output = bundle.run_sh(&[], "echo ran");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("nix directory does not contain a store"),
+        "{stderr}"
+    );
+
+    if !Path::new("/nix/store").is_dir() {
+        eprintln!("skipping the rest: no /nix/store on the host");
+        return;
+    }
+    let output = bundle.run_sh(
+        &["--apprun-merge-host-store"],
+        "test -d /nix/store && echo ran",
+    );
+    assert_eq!(stdout(&output), "ran\n");
+}
+
+#[test]
+fn nix_image_must_be_squashfs() {
+    let bundle = Bundle::new();
+    // Replace the bundled nix directory with a file that isn't an image
+    let nix_dir = bundle.path().join("nix");
+    fs::remove_dir_all(&nix_dir).unwrap();
+    fs::write(&nix_dir, "not a filesystem").unwrap();
+
+    let output = bundle.command().args(["-c", "true"]).output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("is neither a directory nor a squashfs image"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn nix_layers_are_stacked_over_the_bundle() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let base = bundle.add_store_path("base");
+    fs::write(bundle.host_path(&base).join("file"), "base").unwrap();
+    // Layers are images of a nix directory, or the directory itself
+    let layers: Vec<_> = ["lower", "upper"]
+        .iter()
+        .map(|name| {
+            let layer = bundle.path().join(format!("{name}-layer"));
+            let store_path = format!("0123456789abcdfghijklmnpqrsvwxyz-{name}");
+            fs::create_dir_all(layer.join("store").join(&store_path)).unwrap();
+            // Each layer also has a file of the base store path, the upper one's wins
+            let shadowing = layer.join("store").join(base.file_name().unwrap());
+            fs::create_dir_all(&shadowing).unwrap();
+            fs::write(shadowing.join("file"), name).unwrap();
+            layer
+        })
+        .collect();
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .args(
+            layers
+                .iter()
+                .map(|layer| format!("--apprun-nix-layer={}", layer.display())),
+        )
+        .args([
+            "-c",
+            "ls /nix/store; cat /nix/store/*-base/file; echo; test ! -e /.apprun-layers",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(
+        stdout(&output),
+        "0123456789abcdfghijklmnpqrsvwxyz-base\n\
+         0123456789abcdfghijklmnpqrsvwxyz-lower\n\
+         0123456789abcdfghijklmnpqrsvwxyz-upper\n\
+         upper\n"
+    );
+
+    let output = bundle.run_sh(
+        &[&format!(
+            "--apprun-nix-layer={}",
+            bundle.path().join("missing").display()
+        )],
+        "true",
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn nix_layer_images_are_mounted() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: mounting squashfs images needs root");
+        return;
+    }
+    let bundle = Bundle::new();
+    let layer = bundle.path().join("layer");
+    let store_path = "0123456789abcdfghijklmnpqrsvwxyz-layer";
+    fs::create_dir_all(layer.join("store").join(store_path)).unwrap();
+    let image = bundle.path().join("layer.squashfs");
+    let Ok(status) = Command::new("mksquashfs")
+        .args([&layer, &image])
+        .args(["-quiet", "-comp", "zstd"])
+        .status()
+    else {
+        eprintln!("skipping: mksquashfs is not installed");
+        return;
+    };
+    assert!(status.success());
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .arg(format!("--apprun-nix-layer={}", image.display()))
+        .args(["-c", "ls /nix/store; test ! -e /.apprun-layers"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), format!("{store_path}\n"));
+}
+
+#[test]
+fn binds_to_a_different_destination() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let host_dir = bundle.path().join("secrets");
+    fs::create_dir_all(&host_dir).unwrap();
+    fs::write(host_dir.join("token"), "hunter2").unwrap();
+
+    let mut args = minimal_binds();
+    args.push(format!(
+        "--apprun-bind={}:/etc/app/secrets",
+        host_dir.display()
+    ));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = bundle.run_sh(&args, "cat /etc/app/secrets/token; echo; ls /etc");
+    assert_eq!(stdout(&output), "hunter2\napp\n");
+}
+
+#[test]
+fn merges_host_nix_without_host_store() {
+    require_namespaces!();
+    if Path::new("/nix").exists() {
+        eprintln!("skipping: the host has a /nix");
+        return;
+    }
+    let bundle = Bundle::new();
+    let mut expected: Vec<String> = ["hello", "lib", "share"]
+        .iter()
+        .map(|name| {
+            let store_path = bundle.add_store_path(name);
+            let name = store_path.file_name().unwrap().to_str().unwrap();
+            format!("{name}\n")
+        })
+        .collect();
+    expected.sort();
+
+    let output = bundle.run_sh(&["--apprun-merge-host-nix"], "ls /nix/store");
+    assert_eq!(stdout(&output), expected.concat());
+}
+
+#[test]
+fn merged_store_paths_are_bound_in_parallel() {
+    require_namespaces!();
+    if Path::new("/nix").exists() {
+        eprintln!("skipping: the host has a /nix");
+        return;
+    }
+    if std::thread::available_parallelism().map_or(1, |n| n.get()) < 2 {
+        eprintln!("skipping: only one CPU");
+        return;
+    }
+    let bundle = Bundle::new();
+    for i in 0..16 {
+        bundle.add_store_path(&format!("path{i}"));
+    }
+
+    let output = bundle
+        .command()
+        .env("RUST_LOG", "debug")
+        .args(["--apprun-merge-host-nix", "-c", "ls /nix/store | wc -l"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output).trim(), "16");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr
+        .lines()
+        .find(|line| line.contains("Bound 16 paths with"))
+        .unwrap_or_else(|| panic!("{stderr}"));
+    assert!(line.contains("as fast as one at a time"), "{line}");
+}
+
+#[test]
+fn parallel_binds_stress() {
+    require_namespaces!();
+    if Path::new("/nix").exists() {
+        eprintln!("skipping: the host has a /nix");
+        return;
+    }
+    if std::thread::available_parallelism().map_or(1, |n| n.get()) < 2 {
+        eprintln!("skipping: only one CPU");
+        return;
+    }
+    let bundle = Bundle::new();
+    for i in 0..128 {
+        let path = bundle.add_store_path(&format!("path{i}"));
+        fs::create_dir_all(bundle.host_path(&path).join("share/nested")).unwrap();
+    }
+
+    for _ in 0..8 {
+        let output = bundle
+            .command()
+            .args([
+                "--apprun-merge-host-nix",
+                "-c",
+                "ls -d /nix/store/*/share/nested | wc -l",
+            ])
+            .output()
+            .unwrap();
+        assert_eq!(stdout(&output).trim(), "128");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!stderr.contains("Failed to mount"), "{stderr}");
+    }
+}
+
+#[test]
+fn merges_host_nix_preferring_host_paths() {
+    require_namespaces!();
+    let Some(host_path) = fs::read_dir("/nix/store")
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| path.is_dir())
+    else {
+        eprintln!("skipping: no store paths on the host");
+        return;
+    };
+    let bundle = Bundle::new();
+    // The bundled copy of a path the host has is hidden by the host's
+    let shadowed = bundle.host_path(&host_path);
+    fs::create_dir_all(&shadowed).unwrap();
+    fs::write(shadowed.join("apprun-bundled"), "").unwrap();
+    let store_path = bundle.add_store_path("hello");
+
+    let output = bundle.run_sh(
+        &["--apprun-merge-host-nix"],
+        &format!(
+            "test -d {} && test ! -e {}/apprun-bundled && echo ok",
+            store_path.display(),
+            host_path.display()
+        ),
+    );
+    assert_eq!(stdout(&output), "ok\n");
+}
+
+#[test]
+fn binds_host_firmware_readonly() {
+    require_namespaces!();
+    if !Path::new("/lib/firmware").is_dir() {
+        eprintln!("skipping: no /lib/firmware on the host");
+        return;
+    }
+    let bundle = Bundle::new();
+    let mut args = minimal_binds();
+    args.extend([
+        "--apprun-host-firmware".into(),
+        "--apprun-host-modules".into(),
+    ]);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = bundle.run_sh(
+        &args,
+        "test -d /lib/firmware && ! touch /lib/firmware/apprun-test 2>/dev/null && echo ok",
+    );
+    assert_eq!(stdout(&output), "ok\n");
+    assert!(!Path::new("/lib/firmware/apprun-test").exists());
+}
+
+#[test]
+fn cwd_modes() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let cwd = bundle.path().join("cwd");
+    fs::create_dir(&cwd).unwrap();
+    let app_dir = Path::new(env!("CARGO_BIN_EXE_app-run")).parent().unwrap();
+
+    // Without binding /tmp, the working directory isn't visible
+    let binds = minimal_binds();
+    let run_in_cwd = |mode: &str, binds: &[String]| {
+        bundle
+            .command()
+            .current_dir(&cwd)
+            .arg(format!("--apprun-cwd-mode={mode}"))
+            .args(binds)
+            .args(["-c", "pwd"])
+            .output()
+            .unwrap()
+    };
+
+    assert!(!run_in_cwd("preserve", &binds).status.success());
+    assert_eq!(
+        stdout(&run_in_cwd("preserve", &[])),
+        format!("{}\n", cwd.display())
+    );
+    assert_eq!(stdout(&run_in_cwd("preserve-or-root", &binds)), "/\n");
+    assert_eq!(stdout(&run_in_cwd("root", &[])), "/\n");
+    assert_eq!(
+        stdout(&run_in_cwd("bind", &binds)),
+        format!("{}\n", cwd.display())
+    );
+    assert_eq!(
+        stdout(&run_in_cwd("app", &[])),
+        format!("{}\n", app_dir.display())
+    );
+}
+
+#[test]
+fn chdir_inside_container() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let run = |dir: &str| {
+        bundle
+            .command()
+            .arg(format!("--apprun-chdir={dir}"))
+            .args(minimal_binds())
+            .args(["-c", "pwd"])
+            .output()
+            .unwrap()
+    };
+
+    assert_eq!(stdout(&run("/nix/store")), "/nix/store\n");
+    assert_eq!(stdout(&run("usr")), "/usr\n");
+    assert!(!run("/apprun-missing").status.success());
+}
+
+#[test]
+fn audit_logs_operations() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&["--apprun-audit"], "true");
+    stdout(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for call in ["unshare(", "mount(", "pivot_root(", "execve("] {
+        assert!(
+            stderr.contains(&format!("[apprun-audit] {call}")),
+            "{call} missing from {stderr}"
+        );
+    }
+}
+
+#[test]
+fn unmounts_explicit_mount_dir_after_exit() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    // After pivot_root(2), the mount is out of AppRun's reach along with the host's root
+    let output = bundle.run_sh(&["--apprun-audit", "--apprun-use-chroot"], "true");
+    stdout(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mount_dir = bundle.path().join("mountroot");
+    assert!(
+        stderr.contains(&format!(
+            "[apprun-audit] umount2({mount_dir:?}, MNT_DETACH) = ok"
+        )),
+        "cleanup missing from {stderr}"
+    );
+}
+
+#[test]
+fn dry_run_only_logs_mounts() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .env("RUST_LOG", "debug")
+        .args(minimal_binds())
+        .args(["--apprun-bind-try=/apprun-missing", "--apprun-dry-run"])
+        .args(["-c", "echo started"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mount_dir = bundle.path().join("mountroot");
+    assert!(
+        stderr.contains(&format!(
+            "Would mount(Some(\"/usr\"), {:?}",
+            mount_dir.join("usr")
+        )),
+        "bind of /usr missing from {stderr}"
+    );
+    assert!(
+        stderr.contains("\"/apprun-missing\""),
+        "skip missing from {stderr}"
+    );
+    assert_eq!(fs::read_dir(&mount_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn verbose_flags_set_log_level() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let levels = |args: &[&str], rust_log: Option<&str>| {
+        let mut cmd = bundle.command();
+        cmd.env_remove("RUST_LOG").args(args).args(["-c", "true"]);
+        if let Some(rust_log) = rust_log {
+            cmd.env("RUST_LOG", rust_log);
+        }
+        let output = cmd.output().unwrap();
+        stdout(&output);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        ["INFO", "DEBUG"].map(|level| stderr.contains(&format!(" {level} ")))
+    };
+
+    assert_eq!(levels(&[], None), [false, false]);
+    assert_eq!(levels(&["--apprun-verbose"], None), [true, false]);
+    let very_verbose = ["--apprun-verbose", "--apprun-verbose"];
+    assert_eq!(levels(&very_verbose, None), [true, true]);
+    assert_eq!(levels(&very_verbose, Some("error")), [false, false]);
+    assert_eq!(levels(&["--apprun-quiet"], Some("info")), [true, false]);
+}
+
+#[test]
+fn json_log_format_writes_one_object_per_line() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .env("APPRUN_LOG_FORMAT", "json")
+        .arg("--apprun-dry-run")
+        .output()
+        .unwrap();
+    stdout(&output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    for line in stderr.lines() {
+        assert!(
+            line.starts_with(r#"{"timestamp":""#) && line.ends_with('}'),
+            "not a JSON log line: {line}"
+        );
+    }
+    assert!(
+        stderr.contains(r#""level":"INFO","target":"app_run","msg":"Would mount("#),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn signals_during_setup_are_harmless() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let mut child = bundle
+        .command()
+        .args(["-c", "echo done"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = Pid::from_raw(child.id() as i32);
+    // SIGWINCH is ignored by default, so it only interrupts whatever AppRun is doing
+    while child.try_wait().unwrap().is_none() {
+        let _ = kill(pid, Signal::SIGWINCH);
+    }
+    assert_eq!(stdout(&child.wait_with_output().unwrap()), "done\n");
+}
+
+#[test]
+fn gc_root_lives_as_long_as_the_app() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let state_dir = bundle.path().join("state");
+    let gcroots = state_dir.join("gcroots");
+    fs::create_dir_all(&gcroots).unwrap();
+
+    let store_path = bundle.add_store_path("app");
+    let app = bundle.host_path(&store_path).join("app");
+    fs::write(&app, format!("#!/bin/sh\nls {}\n", gcroots.display())).unwrap();
+    fs::set_permissions(&app, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = bundle
+        .command_with_entrypoint(&store_path.join("app"))
+        .arg("--apprun-register-gc-root")
+        .env("NIX_STATE_DIR", &state_dir)
+        .output()
+        .unwrap();
+    let root = stdout(&output);
+    assert!(root.starts_with("apprun-"), "{root}");
+
+    // The root is removed asynchronously once the app has exited
+    let root = gcroots.join(root.trim());
+    for _ in 0..100 {
+        if fs::symlink_metadata(&root).is_err() {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    panic!("GC root {root:?} was not removed after the app exited");
+}
+
+#[test]
+fn baked_env_reaches_entrypoint() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let entrypoint = bundle.path().join("entrypoint");
+    symlink(host_shell().unwrap(), &entrypoint).unwrap();
+    fs::write(
+        bundle.path().join("entrypoint.env"),
+        "# baked by the bundle\nGREETING=hello world\nTERM=dumb\n",
+    )
+    .unwrap();
+    let script = ["-c", r#"echo "$GREETING $TERM""#];
+
+    let output = bundle
+        .command_with_entrypoint(&entrypoint)
+        .args(script)
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "hello world dumb\n");
+
+    // A user-provided environment takes precedence
+    let dump = bundle.path().join("env");
+    fs::write(&dump, "GREETING=bye\n").unwrap();
+    let output = bundle
+        .command_with_entrypoint(&entrypoint)
+        .arg(format!("--apprun-env-from-dump={}", dump.display()))
+        .args(script)
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "bye \n");
+}
+
+#[test]
+fn dumped_environment_loads_back_unchanged() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let dump = bundle.path().join("env");
+    let print_env = ["-c", "env -0"];
+
+    let output = bundle
+        .command()
+        .env("APPRUN_TEST_PLAIN", "hello world")
+        .env("APPRUN_TEST_SPECIAL", "a=b\\c\nd\te \"quoted\" ünïcode")
+        .env("APPRUN_TEST_HASH", "#not a comment")
+        .env("APPRUN_TEST_BYTES", OsStr::from_bytes(b"\xff\xfe"))
+        .arg(format!("--apprun-dump-env={}", dump.display()))
+        .args(print_env)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let dumped = output.stdout;
+    assert!(dumped
+        .split(|&b| b == 0)
+        .any(|entry| entry == b"APPRUN_TEST_BYTES=\xff\xfe"));
+
+    // Nothing from the launching environment is needed to reproduce it
+    let output = bundle
+        .command()
+        .env_clear()
+        .arg(format!("--apprun-env-from-dump={}", dump.display()))
+        .args(print_env)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, dumped);
+}
+
+#[test]
+fn oversized_arguments_fail_clearly() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    // AppRun itself starts with these arguments, but they don't fit in execve(2) together
+    // with the environment from the dump, whose single 256 KiB string never would
+    let dump = bundle.path().join("env");
+    fs::write(&dump, format!("BIG={}\n", "x".repeat(256 * 1024))).unwrap();
+    let args = vec!["x".repeat(1024); 100];
+
+    let output = bundle
+        .command()
+        .arg(format!("--apprun-env-from-dump={}", dump.display()))
+        .args(["-c", "echo ran"])
+        .args(&args)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("argument list too long for execve"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn readonly_bind_all_refuses_writes() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let file = bundle.path().join("written");
+    let script = format!(
+        "touch {} 2>/dev/null && echo written || echo refused",
+        file.display()
+    );
+
+    let output = bundle.run_sh(&["--apprun-readonly-bind-all"], &script);
+    assert_eq!(stdout(&output), "refused\n");
+    assert!(!file.exists());
+
+    let output = bundle.run_sh(&[], &script);
+    assert_eq!(stdout(&output), "written\n");
+    assert!(file.exists());
+}
+
+#[test]
+fn snapshot_lists_assembled_root() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("hello");
+    let snapshot = bundle.path().join("snapshot");
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .arg(format!("--apprun-snapshot={}", snapshot.display()))
+        .arg("-c")
+        .arg("echo ran")
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "");
+
+    let listing = fs::read_to_string(&snapshot).unwrap();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert!(lines.contains(&format!("{}/", store_path.display()).as_str()));
+    assert!(lines.contains(&"/usr/"));
+    assert!(!lines.contains(&"/etc/"));
+}
+
+#[test]
+fn audit_labels_store_mount() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&["--apprun-audit", "--apprun-store-label=my-app"], "true");
+    stdout(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let nix_dir = bundle.path().join("nix");
+    assert!(
+        stderr.contains(&format!("[apprun-audit] # my-app: {nix_dir:?}")),
+        "store label missing from {stderr}"
+    );
+}
+
+/// Run a command string given to --apprun-entrypoint-shell-words,
+/// which gets the words printed by the host shell
+fn run_shell_words(command: &str) -> Output {
+    let bundle = Bundle::new();
+    let shell = host_shell().unwrap();
+    bundle
+        .command_without_entrypoint()
+        .arg(format!(
+            "--apprun-entrypoint-shell-words={} -c 'printf \"[%s]\" \"$@\"' {command}",
+            shell.display()
+        ))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn entrypoint_args_come_before_user_args() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .args([
+            "--apprun-entrypoint-arg=-c",
+            r#"--apprun-entrypoint-arg=printf "[%s]" "$@""#,
+            "--apprun-entrypoint-arg=sh",
+            "--apprun-entrypoint-arg=fixed  'arg'",
+            "user arg",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "[fixed  'arg'][user arg]");
+
+    let output = bundle
+        .command_without_entrypoint()
+        .arg(format!(
+            "--apprun-entrypoint-shell-words={} -c 'printf \"[%s]\" \"$@\"' sh",
+            host_shell().unwrap().display()
+        ))
+        .args(["--apprun-entrypoint-arg=--fixed", "user"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "[--fixed][user]");
+}
+
+#[test]
+fn shell_words_entrypoint_splits_quotes() {
+    require_namespaces!();
+
+    let output = run_shell_words(r#"sh 'single quoted' "double \"quoted\" $HOME" mixed'  'word"#);
+    assert_eq!(
+        stdout(&output),
+        r#"[single quoted][double "quoted" $HOME][mixed  word]"#
+    );
+}
+
+#[test]
+fn shell_words_entrypoint_handles_escapes_and_whitespace() {
+    require_namespaces!();
+
+    let output = run_shell_words("sh  \t a\\ b \\\\ \\'c\\'  ''  \n\t  d ");
+    assert_eq!(stdout(&output), "[a b][\\]['c'][][d]");
+}
+
+#[test]
+fn shell_words_entrypoint_rejects_unterminated_quote() {
+    require_namespaces!();
+
+    let output = run_shell_words("sh 'oops");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn no_slave_root_skips_remount() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let is_slave_remount = |output: &Output| {
+        String::from_utf8_lossy(&output.stderr).contains("[apprun-audit] mount(None, \"/\"")
+    };
+
+    let output = bundle.run_sh(&["--apprun-audit"], "true");
+    stdout(&output);
+    assert!(is_slave_remount(&output));
+
+    // Arrange the propagation ourselves, so that nothing leaks into the host when run as root
+    let mut cmd = bundle.command();
+    if Uid::effective().is_root() {
+        with_root_propagation(&mut cmd, MsFlags::MS_PRIVATE);
+    }
+    let output = cmd
+        .args(["--apprun-audit", "--apprun-no-slave-root", "-c", "true"])
+        .output()
+        .unwrap();
+    stdout(&output);
+    assert!(!is_slave_remount(&output));
+}
+
+#[test]
+fn proc_mask_hides_sensitive_files() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .args([
+            "--apprun-bind=/proc",
+            "--apprun-bind=/dev",
+            "--apprun-proc-mask",
+        ])
+        .arg("-c")
+        .arg(
+            "for f in kcore keys sysrq-trigger timer_list; do \
+               [ ! -e /proc/$f ] || grep -q \" /proc/$f \" /proc/self/mountinfo || echo $f not masked; \
+             done; \
+             grep -c ' /proc/sys ro,' /proc/self/mountinfo",
+        )
+        .output()
+        .unwrap();
+    // Not every kernel has all of the masked files
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn proc_hidepid_mounts_fresh_proc() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: mounting proc without root needs a PID namespace");
+        return;
+    }
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .arg("--apprun-proc-hidepid")
+        .arg("-c")
+        .arg("grep ' /proc ' /proc/self/mountinfo | grep -o 'hidepid=[a-z0-9]*'")
+        .output()
+        .unwrap();
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("hidepid="), "{stdout}");
+}
+
+#[test]
+fn copy_entrypoint_runs_without_app_dir() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let shim = bundle.path().join("shim");
+    fs::write(
+        &shim,
+        format!(
+            "#!{}\necho copied \"$@\"\n",
+            host_shell().unwrap().display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&shim, fs::Permissions::from_mode(0o755)).unwrap();
+
+    // The bundle directory isn't bound, so the shim can't be run from there
+    let output = bundle
+        .command_with_entrypoint(&shim)
+        .args(minimal_binds())
+        .arg("arg")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    let output = bundle
+        .command_with_entrypoint(&shim)
+        .args(minimal_binds())
+        .arg("--apprun-copy-entrypoint")
+        .arg("arg")
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "copied arg\n");
+}
+
+#[test]
+fn term_is_inherited_from_host() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .env("TERM", "vt100")
+        .args(["--apprun-term=dumb", "-c", r#"echo "${TERM-unset}""#])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "vt100\n");
+}
+
+#[test]
+fn term_is_unset_without_terminal() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .env_remove("TERM")
+        .args(["-c", r#"echo "${TERM-unset}""#])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "unset\n");
+}
+
+#[test]
+fn term_falls_back_in_terminal() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let out = bundle.path().join("term");
+
+    let run = |apprun_args: &[&str]| {
+        let pty = openpty(None, None).unwrap();
+        let status = bundle
+            .command()
+            .env_remove("TERM")
+            .stdout(unsafe { Stdio::from_raw_fd(pty.slave) })
+            .args(apprun_args)
+            .arg("-c")
+            .arg(format!(r#"echo "${{TERM-unset}}" > {}"#, out.display()))
+            .status()
+            .unwrap();
+        close(pty.master).unwrap();
+        assert!(status.success());
+        fs::read_to_string(&out).unwrap()
+    };
+
+    assert_eq!(run(&[]), "xterm-256color\n");
+    assert_eq!(run(&["--apprun-term=xterm"]), "xterm\n");
+}
+
+#[test]
+fn mount_dir_falls_back_to_tmpdir() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    // An AppDir without mountroot, using the default paths next to AppRun
+    fs::remove_dir(bundle.path().join("mountroot")).unwrap();
+    let apprun = bundle.path().join("AppRun");
+    fs::copy(env!("CARGO_BIN_EXE_app-run"), &apprun).unwrap();
+    symlink(host_shell().unwrap(), bundle.path().join("entrypoint")).unwrap();
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    let output = Command::new(&apprun)
+        .current_dir("/")
+        .env("TMPDIR", tmpdir.path())
+        .args(["-c", "echo ok"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "ok\n");
+    let fallback = tmpdir
+        .path()
+        .join(format!("apprun-mountroot-{}", Uid::current()));
+    assert!(fallback.is_dir());
+    assert_eq!(fs::metadata(&fallback).unwrap().mode() & 0o777, 0o700);
+
+    // One that anyone could have created in its place is refused
+    fs::set_permissions(&fallback, fs::Permissions::from_mode(0o777)).unwrap();
+    let output = Command::new(&apprun)
+        .current_dir("/")
+        .env("TMPDIR", tmpdir.path())
+        .args(["-c", "echo ok"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is not a private directory"), "{stderr}");
+}
+
+#[test]
+fn xdg_runtime_dir_is_private_tmpfs() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let uid = Uid::effective();
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .args([
+            "--apprun-xdg-runtime",
+            "-c",
+            r#"echo "$XDG_RUNTIME_DIR"; stat -c '%a %u' "$XDG_RUNTIME_DIR""#,
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), format!("/run/user/{uid}\n700 {uid}\n"));
+}
+
+#[test]
+fn maps_current_user_to_root() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&["--apprun-map-root"], "echo $(id -u) $(id -g)");
+    assert_eq!(stdout(&output), "0 0\n");
+}
+
+#[test]
+fn argv0_is_set_without_user_args() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    // A shell reading its script from stdin reports argv[0] as $0
+    let mut child = bundle
+        .command()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), b"echo \"$0\"\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(
+        stdout(&output),
+        format!("{}\n", env!("CARGO_BIN_EXE_app-run"))
+    );
+}
+
+#[test]
+fn daemonize_detaches_and_keeps_running() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let out = bundle.path().join("daemon");
+
+    let output = bundle
+        .command()
+        .args(["--apprun-daemonize", "--apprun-umask=027", "-c"])
+        .arg(format!(
+            "sleep 0.5; echo \"$(umask) $(cut -d' ' -f6 /proc/$$/stat)\" > {}",
+            out.display()
+        ))
+        .output()
+        .unwrap();
+    // AppRun returns right away, leaving the daemon running
+    let pid: i32 = stdout(&output).trim().parse().unwrap();
+    kill(Pid::from_raw(pid), None).unwrap();
+
+    for _ in 0..50 {
+        if let Ok(contents) = fs::read_to_string(&out) {
+            if contents.ends_with('\n') {
+                let (mask, sid) = contents.trim().split_once(' ').unwrap();
+                assert_eq!(mask, "0027");
+                // The daemon is in a new session, but doesn't lead it
+                let sid = Pid::from_raw(sid.parse().unwrap());
+                assert_ne!(sid, getsid(None).unwrap());
+                assert_ne!(sid, Pid::from_raw(pid));
+                return;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    panic!("daemon didn't finish");
+}
+
+#[test]
+fn verify_propagation_catches_shared_mounts() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: setting up shared mounts needs root");
+        return;
+    }
+    let bundle = Bundle::new();
+    let run = |apprun_args: &[&str]| {
+        let mut cmd = bundle.command();
+        with_root_propagation(&mut cmd, MsFlags::MS_SHARED);
+        cmd.args(apprun_args)
+            .args(["--apprun-no-slave-root", "-c", "echo ran"])
+            .output()
+            .unwrap()
+    };
+
+    // Only a warning by default
+    let output = run(&[]);
+    assert_eq!(stdout(&output), "ran\n");
+
+    let output = run(&["--apprun-verify-propagation"]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let output = bundle.run_sh(&["--apprun-verify-propagation"], "echo ran");
+    assert_eq!(stdout(&output), "ran\n");
+}
+
+#[test]
+fn profile_link_below_host_dir_leaves_host_alone() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("system");
+    let host_link = fs::read_link("/run/current-system").ok();
+    let mut expected: Vec<String> = fs::read_dir("/run")
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .chain(["current-system".to_string()])
+        .collect();
+    expected.sort();
+    expected.dedup();
+
+    let output = bundle.run_sh(
+        &[&format!(
+            "--apprun-profile-link=/run/current-system:{}",
+            store_path.display()
+        )],
+        "readlink /run/current-system; ls -A /run",
+    );
+    let output = stdout(&output);
+    let mut lines = output.lines();
+    assert_eq!(lines.next(), store_path.to_str());
+    // The host's entries are still there
+    let mut entries: Vec<&str> = lines.collect();
+    entries.sort();
+    assert_eq!(entries, expected);
+    assert_eq!(fs::read_link("/run/current-system").ok(), host_link);
+}
+
+#[test]
+fn exec_search_finds_command_in_profile() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("hello");
+    let bin = bundle.host_path(&store_path).join("bin");
+    fs::create_dir(&bin).unwrap();
+    fs::write(
+        bin.join("hello"),
+        format!("#!{}\necho hello \"$@\"\n", host_shell().unwrap().display()),
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("hello"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let run = |option: &str, name: &str| {
+        bundle
+            .command_without_entrypoint()
+            .env("PATH", "/usr/bin:/profile/bin")
+            .args(minimal_binds())
+            .arg(format!(
+                "--apprun-profile-link=/profile:{}",
+                store_path.display()
+            ))
+            .arg(format!("--apprun-{option}={name}"))
+            .arg("world")
+            .output()
+            .unwrap()
+    };
+
+    assert_eq!(stdout(&run("exec-search", "hello")), "hello world\n");
+    // An entrypoint without a slash is searched for too
+    assert_eq!(stdout(&run("entrypoint", "hello")), "hello world\n");
+
+    let output = run("exec-search", "goodbye");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("command not found in container PATH"));
+}
+
+#[test]
+fn bare_entrypoint_is_searched_in_path() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let store_path = bundle.add_store_path("hello");
+    let bin = bundle.host_path(&store_path).join("bin");
+    fs::create_dir(&bin).unwrap();
+    fs::write(
+        bin.join("hello"),
+        format!("#!{}\necho hello \"$@\"\n", host_shell().unwrap().display()),
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("hello"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = bundle
+        .command_without_entrypoint()
+        .env("PATH", "/usr/bin:/profile/bin")
+        .args(minimal_binds())
+        .arg(format!(
+            "--apprun-profile-link=/profile:{}",
+            store_path.display()
+        ))
+        .args(["--apprun-entrypoint=hello", "world"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "hello world\n");
+}
+
+#[test]
+fn runs_from_appdir_with_non_utf8_path() {
+    require_namespaces!();
+    let dir = tempfile::tempdir().unwrap();
+    let appdir = dir.path().join(OsStr::from_bytes(b"app\xf6"));
+    fs::create_dir_all(appdir.join("nix/store")).unwrap();
+    fs::create_dir(appdir.join("mountroot")).unwrap();
+    fs::copy(env!("CARGO_BIN_EXE_app-run"), appdir.join("AppRun")).unwrap();
+    symlink(host_shell().unwrap(), appdir.join("entrypoint")).unwrap();
+    // Arguments still have to be UTF-8, so it's started through a link
+    let apprun = dir.path().join("AppRun");
+    symlink(appdir.join("AppRun"), &apprun).unwrap();
+
+    let output = Command::new(&apprun)
+        .current_dir("/")
+        .args(["-c", "echo ok"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "ok\n");
+}
+
+#[test]
+fn metrics_file_reports_launch() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let path = bundle.path().join("metrics.prom");
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .arg(format!("--apprun-metrics-file={}", path.display()))
+        .args(["-c", "exit 7"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7), "{output:?}");
+
+    let metrics = fs::read_to_string(&path).unwrap();
+    let value = |name: &str| -> f64 {
+        let line = metrics
+            .lines()
+            .find(|line| line.starts_with(&format!("{name} ")))
+            .unwrap_or_else(|| panic!("{name} missing from {metrics}"));
+        line[name.len() + 1..].parse().unwrap()
+    };
+    let launch = value("apprun_launch_seconds");
+    let mount = value("apprun_mount_seconds");
+    assert!(0.0 < mount && mount <= launch && launch < 60.0);
+    // The minimal binds and /nix
+    assert_eq!(value("apprun_binds"), (minimal_binds().len() + 1) as f64);
+    assert_eq!(value("apprun_failed_binds"), 0.0);
+    assert_eq!(value("apprun_exit_code"), 7.0);
+
+    // Nothing is left to report the exit code once the entrypoint replaced AppRun
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .arg(format!("--apprun-metrics-file={}", path.display()))
+        .args(["--apprun-exec-replace", "-c", "exit 7"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7), "{output:?}");
+    let metrics = fs::read_to_string(&path).unwrap();
+    assert!(metrics.contains("apprun_launch_seconds "), "{metrics}");
+    assert!(!metrics.contains("apprun_exit_code"), "{metrics}");
+}
+
+#[test]
+fn exit_code_is_propagated() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle.run_sh(&[], "exit 3");
+    assert_eq!(output.status.code(), Some(3));
+
+    // Like a shell, report a signal as 128 plus its number
+    let output = bundle.run_sh(&[], "kill -TERM $$");
+    assert_eq!(output.status.code(), Some(128 + Signal::SIGTERM as i32));
+}
+
+#[test]
+fn signals_are_forwarded_to_entrypoint() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let mut child = bundle
+        .command()
+        .args([
+            "-c",
+            "trap 'echo got TERM; exit 7' TERM; echo ready; while :; do sleep 0.1; done",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut output = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    output.read_line(&mut line).unwrap();
+    assert_eq!(line, "ready\n");
+
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+    line.clear();
+    output.read_line(&mut line).unwrap();
+    assert_eq!(line, "got TERM\n");
+    assert_eq!(child.wait().unwrap().code(), Some(7));
+}
+
+#[test]
+fn init_entrypoint_is_killed_after_grace_period() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    // As PID 1 without a handler, the shell ignores SIGTERM
+    let mut child = bundle
+        .command()
+        .args([
+            "--apprun-unshare-pid",
+            "-c",
+            "echo ready; while :; do sleep 0.1; done",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut output = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    output.read_line(&mut line).unwrap();
+    assert_eq!(line, "ready\n");
+
+    let started = std::time::Instant::now();
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+    assert_eq!(
+        child.wait().unwrap().code(),
+        Some(128 + Signal::SIGKILL as i32)
+    );
+    assert!(started.elapsed() >= std::time::Duration::from_secs(9));
+}
+
+#[test]
+fn forward_signal_passes_on_reload_signals() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let mut child = bundle
+        .command()
+        .args([
+            "--apprun-forward-signal=SIGUSR1",
+            "-c",
+            "trap 'echo got HUP' HUP; trap 'echo got USR1; exit 5' USR1; \
+             echo ready; while :; do sleep 0.1; done",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = Pid::from_raw(child.id() as i32);
+    let mut output = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    output.read_line(&mut line).unwrap();
+    assert_eq!(line, "ready\n");
+
+    for (signal, expected) in [
+        (Signal::SIGHUP, "got HUP\n"),
+        (Signal::SIGUSR1, "got USR1\n"),
+    ] {
+        kill(pid, signal).unwrap();
+        line.clear();
+        output.read_line(&mut line).unwrap();
+        assert_eq!(line, expected);
+    }
+    assert_eq!(child.wait().unwrap().code(), Some(5));
+
+    let output = bundle.run_sh(&["--apprun-forward-signal=SIGTERM"], "true");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("expected SIGHUP, SIGUSR1 or SIGUSR2"));
+}
+
+#[test]
+fn wait_children_outlives_grandchildren() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let marker = bundle.path().join("marker");
+    // The grandchild lets go of stdout, so only AppRun itself is waited for
+    let script = |seconds: &str| {
+        format!(
+            "(sleep {seconds}; touch {}) >/dev/null 2>&1 & exit 3",
+            marker.display()
+        )
+    };
+    let run = |apprun_args: &[&str], seconds: &str| {
+        let _ = fs::remove_file(&marker);
+        let output = bundle.run_sh(apprun_args, &script(seconds));
+        assert_eq!(output.status.code(), Some(3));
+        (
+            marker.exists(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        )
+    };
+
+    assert!(!run(&[], "0.5").0);
+    assert!(run(&["--apprun-wait-children=10"], "0.5").0);
+
+    let (exists, stderr) = run(&["--apprun-wait-children=0.2"], "1");
+    assert!(!exists);
+    assert!(stderr.contains("still running after"), "{stderr}");
+}
+
+/// Create a memory cgroup below the current one, limited to limit bytes without swap,
+/// returning its directory, or None if that isn't possible
+fn limited_memory_cgroup(limit: u64) -> Option<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let (parent, settings) = match cgroups.lines().find_map(|line| line.split_once(":memory:")) {
+        // The v1 swap limit is on memory and swap together
+        Some((_, path)) => (
+            Path::new("/sys/fs/cgroup/memory").join(path.trim_start_matches('/')),
+            [
+                ("memory.limit_in_bytes", limit),
+                ("memory.memsw.limit_in_bytes", limit),
+            ],
+        ),
+        None => (
+            Path::new("/sys/fs/cgroup")
+                .join(cgroups.lines().find_map(|line| line.strip_prefix("0::/"))?),
+            [("memory.max", limit), ("memory.swap.max", 0)],
+        ),
+    };
+    let dir = parent.join(format!("apprun-test-{}", std::process::id()));
+    fs::create_dir(&dir).ok()?;
+    let [memory, swap] = settings.map(|(file, value)| fs::write(dir.join(file), value.to_string()));
+    // Without swap accounting there is no swap to limit
+    if memory.is_err() || swap.is_err() && dir.join(settings[1].0).exists() {
+        let _ = fs::remove_dir(&dir);
+        return None;
+    }
+    Some(dir)
+}
+
+#[test]
+fn signal_on_oom_reports_oom_killed_app() {
+    require_namespaces!();
+    if !Uid::effective().is_root() {
+        eprintln!("skipping: needs root to create a cgroup");
+        return;
+    }
+    let Some(cgroup) = limited_memory_cgroup(32 << 20) else {
+        eprintln!("skipping: cannot create a memory-limited cgroup");
+        return;
+    };
+    let procs = CString::new(cgroup.join("cgroup.procs").as_os_str().as_bytes()).unwrap();
+    let bundle = Bundle::new();
+    let run = |script: &str| {
+        let mut cmd = bundle.command();
+        unsafe {
+            let procs = procs.clone();
+            cmd.pre_exec(move || {
+                let fd = open(procs.as_c_str(), OFlag::O_WRONLY, Mode::empty())?;
+                write(fd, b"0")?;
+                close(fd)?;
+                Ok(())
+            });
+        }
+        cmd.args(["--apprun-signal-on-oom", "-c", script])
+            .output()
+            .unwrap()
+    };
+
+    let output = run("echo ok");
+    assert_eq!(stdout(&output), "ok\n");
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("OOM"));
+
+    // The shell collects far more than the limit in a variable
+    let output = run("x=$(head -c 256M /dev/zero | tr '\\0' a); echo survived");
+    // The rest of the pipeline may still be on its way out
+    for _ in 0..50 {
+        if fs::remove_dir(&cgroup).is_ok() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(output.status.code(), Some(251));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("killed by the OOM killer"), "{stderr}");
+}
+
+#[test]
+fn exec_replace_runs_entrypoint_in_place() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let child = bundle
+        .command()
+        .args(["--apprun-exec-replace", "-c", "echo $$"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(stdout(&output), format!("{pid}\n"));
+
+    let output = bundle.run_sh(&["--apprun-exec-replace"], "exit 3");
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn host_env_is_forwarded() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let script = r#"echo "${GREETING-unset} ${TERM-unset}""#;
+
+    let output = bundle
+        .command()
+        .env("GREETING", "hello")
+        .env("TERM", "vt100")
+        .args(["-c", script])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "hello vt100\n");
+
+    // Only TERM is kept with a minimal environment
+    let output = bundle
+        .command()
+        .env("GREETING", "hello")
+        .env("TERM", "vt100")
+        .args(["--apprun-clearenv", "-c", script])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "unset vt100\n");
+}
+
+#[test]
+fn setenv_and_unsetenv_adjust_environment() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .env("LC_ALL", "en_US.UTF-8")
+        .env("LD_PRELOAD", "libfoo.so")
+        .args([
+            "--apprun-setenv=LC_ALL=C",
+            "--apprun-setenv=GREETING=a=b",
+            "--apprun-unsetenv=LD_PRELOAD",
+            "-c",
+            r#"echo "$LC_ALL $GREETING ${LD_PRELOAD-unset}""#,
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "C a=b unset\n");
+
+    let output = bundle.run_sh(&["--apprun-setenv=NOVALUE"], "true");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn config_file_sets_defaults_for_flags() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let config = bundle.path().join("apprun.toml");
+    fs::write(
+        &config,
+        format!(
+            "entrypoint = {:?}\nunshare_uts = true\nhostname = \"configured\"\n\n\
+             [env]\nGREETING = \"hi\"\nNAME = \"config\"\n",
+            host_shell().unwrap()
+        ),
+    )
+    .unwrap();
+    let config_arg = format!("--apprun-config={}", config.display());
+
+    let output = bundle
+        .command_without_entrypoint()
+        .args([
+            &config_arg,
+            "--apprun-setenv=NAME=cli",
+            "-c",
+            r#"echo "$(uname -n) $GREETING $NAME""#,
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "configured hi cli\n");
+
+    let output = bundle.run_sh(&[&config_arg, "--apprun-hostname=flag"], "uname -n");
+    assert_eq!(stdout(&output), "flag\n");
+
+    fs::write(&config, "no_such_key = 1\n").unwrap();
+    let output = bundle.run_sh(&[&config_arg], "true");
+    assert!(!output.status.success());
+
+    // Like --apprun-unshare-pid, which clap rejects together with --apprun-exec-replace
+    fs::write(&config, "unshare_pid = true\n").unwrap();
+    let output = bundle.run_sh(&[&config_arg, "--apprun-exec-replace"], "true");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("cannot be used with --apprun-exec-replace"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn config_profile_is_chosen_by_invoked_name() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let config = bundle.path().join("apprun.toml");
+    fs::write(
+        &config,
+        format!(
+            "entrypoint = {:?}\n\n[env]\nNAME = \"default\"\n\n\
+             [profiles.tool-a.env]\nNAME = \"a\"\n\n\
+             [profiles.tool-b]\nunshare_uts = true\nhostname = \"b\"\n",
+            host_shell().unwrap()
+        ),
+    )
+    .unwrap();
+    let run = |name: &str| {
+        let link = bundle.path().join(name);
+        symlink(env!("CARGO_BIN_EXE_app-run"), &link).unwrap();
+        // The same command, started under another name
+        let apprun = bundle.command_without_entrypoint();
+        let output = Command::new(&link)
+            .current_dir("/")
+            .args(apprun.get_args())
+            .arg(format!("--apprun-config={}", config.display()))
+            .args(["-c", r#"echo "$NAME $(uname -n)""#])
+            .output()
+            .unwrap();
+        stdout(&output)
+    };
+    let host = nix::unistd::gethostname().unwrap();
+
+    assert_eq!(run("tool-a"), format!("a {}\n", host.to_string_lossy()));
+    assert_eq!(run("tool-b"), "default b\n");
+    assert_eq!(
+        run("other"),
+        format!("default {}\n", host.to_string_lossy())
+    );
+}
+
+#[test]
+fn config_mounts_are_made_in_container() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let config = bundle.path().join("apprun.toml");
+    let config_arg = format!("--apprun-config={}", config.display());
+    let run = |mounts: &str, script: &str| {
+        fs::write(&config, format!("mounts = [{mounts}]\n")).unwrap();
+        bundle.run_sh(&[&config_arg], script)
+    };
+
+    let output = run(
+        r#"{ fstype = "tmpfs", target = "/scratch", options = "nodev,mode=700,size=1M" }"#,
+        "stat -f -c %T /scratch; stat -c %a /scratch",
+    );
+    assert_eq!(stdout(&output), "tmpfs\n700\n");
+
+    // Nothing is created in a directory bound from the host
+    let output = run(
+        r#"{ fstype = "tmpfs", target = "/usr/apprun-missing" }"#,
+        "true",
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is bound from the host"), "{stderr}");
+    assert!(!Path::new("/usr/apprun-missing").exists());
+
+    let output = run(r#"{ fstype = "tmpfs", target = "scratch" }"#, "true");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("must be an absolute path below /"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn pivot_root_detaches_host_root() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let audited = |output: &Output, call: &str| {
+        String::from_utf8_lossy(&output.stderr).contains(&format!("[apprun-audit] {call}("))
+    };
+
+    // Nothing is left of the old root
+    let output = bundle.run_sh(&["--apprun-audit"], "ls -A /.apprun");
+    assert_eq!(stdout(&output), "");
+    assert!(audited(&output, "pivot_root"));
+    assert!(!audited(&output, "chroot"));
+
+    let output = bundle.run_sh(&["--apprun-audit", "--apprun-use-chroot"], "true");
+    stdout(&output);
+    assert!(!audited(&output, "pivot_root"));
+    assert!(audited(&output, "chroot"));
+}
+
+#[test]
+fn unshare_pid_runs_entrypoint_as_pid_1() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .args([
+            "--apprun-unshare-pid",
+            "-c",
+            "echo $$; ls /proc | grep -c '^[0-9]'",
+        ])
+        .output()
+        .unwrap();
+    let stdout = stdout(&output);
+    let (pid, processes) = stdout.trim().split_once('\n').unwrap();
+    assert_eq!(pid, "1");
+    // Only the shell and the pipeline are visible
+    assert!(processes.parse::<usize>().unwrap() < 5, "{processes}");
+}
+
+#[test]
+fn unshare_net_has_only_loopback() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+
+    let output = bundle
+        .command()
+        .args(minimal_binds())
+        .args([
+            "--apprun-bind=/proc",
+            "--apprun-unshare-net",
+            "-c",
+            // fib_trie only has 127.0.0.1 once lo is up
+            "tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' '; grep -q 127.0.0.1 /proc/net/fib_trie && echo up",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "lo\nup\n");
+}
+
+#[test]
+fn unshare_ipc_and_cgroup_isolate_the_app() {
+    require_namespaces!();
+    if !Path::new("/dev/shm").is_dir() {
+        eprintln!("skipping: no /dev/shm on the host");
+        return;
+    }
+    let bundle = Bundle::new();
+    let marker = tempfile::Builder::new()
+        .prefix("apprun-test-")
+        .tempfile_in("/dev/shm")
+        .unwrap();
+    let name = marker.path().file_name().unwrap().to_str().unwrap();
+
+    let script = format!(
+        "readlink /proc/self/ns/ipc /proc/self/ns/cgroup; test -e /dev/shm/{name} && echo shared; true"
+    );
+    let output = bundle.run_sh(&[], &script);
+    assert!(stdout(&output).ends_with("shared\n"));
+
+    let output = bundle.run_sh(
+        &["--apprun-unshare-ipc", "--apprun-unshare-cgroup"],
+        &script,
+    );
+    let output = stdout(&output);
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 2, "{output}");
+    for (line, name) in lines.iter().zip(["ipc", "cgroup"]) {
+        let host = fs::read_link(format!("/proc/self/ns/{name}")).unwrap();
+        assert_ne!(Path::new(line), host);
+    }
+}
+
+#[test]
+fn unshare_list_creates_namespaces() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let host_ns = |name: &str| {
+        fs::read_link(format!("/proc/self/ns/{name}"))
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+
+    let output = bundle.run_sh(
+        &["--apprun-unshare=mount,ipc,cgroup,uts"],
+        "readlink /proc/self/ns/ipc /proc/self/ns/cgroup /proc/self/ns/net; uname -n",
+    );
+    let lines: Vec<_> = stdout(&output).lines().map(str::to_string).collect();
+    assert_ne!(lines[0], host_ns("ipc"));
+    assert_ne!(lines[1], host_ns("cgroup"));
+    assert_eq!(lines[2], host_ns("net"));
+    assert_eq!(lines[3], "appimage");
+
+    let output = bundle.run_sh(&["--apprun-unshare=ipc,user"], "true");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("possible values"));
+}
+
+#[test]
+fn ro_bind_makes_paths_readonly() {
+    require_namespaces!();
+    let bundle = Bundle::new();
+    let ro = bundle.path().join("ro");
+    fs::create_dir(&ro).unwrap();
+    let try_touch = |apprun_args: &[String], dir: &Path| {
+        let script = format!(
+            "touch {}/file 2>/dev/null && echo written || echo refused",
+            dir.display()
+        );
+        let output = bundle
+            .command()
+            .args(apprun_args)
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+        stdout(&output)
+    };
+
+    // A directory below a bound root directory
+    let args = [format!("--apprun-ro-bind={}", ro.display())];
+    assert_eq!(try_touch(&args, &ro), "refused\n");
+    assert_eq!(try_touch(&args, bundle.path()), "written\n");
+
+    // A bound root directory
+    let tmp = std::env::temp_dir();
+    let mut args = minimal_binds();
+    args.push(format!("--apprun-bind={}", tmp.display()));
+    args.push(format!("--apprun-ro-bind={}", tmp.display()));
+    assert_eq!(try_touch(&args, bundle.path()), "refused\n");
+}